@@ -0,0 +1,681 @@
+//! Support code for the expansion of [`c!`](crate::c). Not public API.
+
+use std::collections::{btree_map, hash_map, BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::iter::{Rev, Skip, StepBy, Take};
+#[cfg(feature = "futures")]
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "futures")]
+pub use futures;
+#[cfg(feature = "indexmap")]
+pub use indexmap;
+#[cfg(feature = "rayon")]
+pub use rayon;
+
+/// Fills a `[T; N]` one element at a time without allocating.
+pub struct ArrayBuilder<T, const N: usize> {
+    items: [Option<T>; N],
+    len: usize,
+}
+
+impl<T, const N: usize> ArrayBuilder<T, N> {
+    pub fn new() -> Self {
+        ArrayBuilder {
+            items: core::array::from_fn(|_| None),
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.len == N {
+            panic!("array comprehension produced more than {} elements", N);
+        }
+        self.items[self.len] = Some(item);
+        self.len += 1;
+    }
+
+    pub fn finish(self) -> [T; N] {
+        if self.len != N {
+            panic!(
+                "array comprehension produced {} elements, expected {}",
+                self.len, N
+            );
+        }
+        self.items.map(Option::unwrap)
+    }
+}
+
+impl<T, const N: usize> Default for ArrayBuilder<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes items separated by `sep`.
+pub struct Joiner<S> {
+    sep: S,
+    first: bool,
+}
+
+impl<S: fmt::Display> Joiner<S> {
+    pub fn new(sep: S) -> Self {
+        Joiner { sep, first: true }
+    }
+
+    fn write_sep<W: fmt::Write>(&mut self, w: &mut W) -> fmt::Result {
+        if !self.first {
+            write!(w, "{}", self.sep)?;
+        }
+        self.first = false;
+        Ok(())
+    }
+
+    pub fn push<T: fmt::Display>(&mut self, s: &mut String, item: T) {
+        use std::fmt::Write;
+
+        // Writing to a `String` can't fail
+        let _ = self.write_sep(s);
+        let _ = write!(s, "{}", item);
+    }
+
+    /// Like `push`, but passes the formatter's flags (width, precision, etc.)
+    /// on to `item`.
+    pub fn fmt<T: fmt::Display>(&mut self, f: &mut fmt::Formatter, item: T) -> fmt::Result {
+        self.write_sep(f)?;
+        fmt::Display::fmt(&item, f)
+    }
+}
+
+/// A value that formats itself by calling `F`.
+pub struct Display<F>(F);
+
+pub fn display<F: Fn(&mut fmt::Formatter) -> fmt::Result>(f: F) -> Display<F> {
+    Display(f)
+}
+
+impl<F: Fn(&mut fmt::Formatter) -> fmt::Result> fmt::Display for Display<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (self.0)(f)
+    }
+}
+
+/// Mutably reborrows an `into` target. Called with method syntax, so the
+/// target can be either a collection or a mutable reference to one.
+pub trait Target {
+    fn target(&mut self) -> &mut Self {
+        self
+    }
+}
+
+impl<T: ?Sized> Target for T {}
+
+/// Keeps the elements for which `F` returns `Some`, replacing them with the
+/// returned value. `F` receives `&T` for sequences and `(&K, &V)` for maps.
+pub trait Update<F> {
+    fn update(&mut self, f: F);
+}
+
+impl<T, F: FnMut(&T) -> Option<T>> Update<F> for Vec<T> {
+    fn update(&mut self, mut f: F) {
+        self.retain_mut(|x| replace(x, f(x)));
+    }
+}
+
+impl<T, F: FnMut(&T) -> Option<T>> Update<F> for VecDeque<T> {
+    fn update(&mut self, mut f: F) {
+        self.retain_mut(|x| replace(x, f(x)));
+    }
+}
+
+impl<K, V, S, F: FnMut((&K, &V)) -> Option<V>> Update<F> for HashMap<K, V, S> {
+    fn update(&mut self, mut f: F) {
+        self.retain(|k, v| replace(v, f((k, v))));
+    }
+}
+
+impl<K: Ord, V, F: FnMut((&K, &V)) -> Option<V>> Update<F> for BTreeMap<K, V> {
+    fn update(&mut self, mut f: F) {
+        self.retain(|k, v| replace(v, f((k, v))));
+    }
+}
+
+#[cfg(feature = "indexmap")]
+impl<K, V, S, F: FnMut((&K, &V)) -> Option<V>> Update<F> for indexmap::IndexMap<K, V, S> {
+    fn update(&mut self, mut f: F) {
+        self.retain(|k, v| replace(v, f((k, v))));
+    }
+}
+
+fn replace<T>(slot: &mut T, value: Option<T>) -> bool {
+    match value {
+        Some(value) => {
+            *slot = value;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Entry-style updates shared by the map types that accumulating map
+/// comprehensions can build.
+pub trait Map<K, V> {
+    /// Inserts `v`, or replaces the existing value `old` with `f(old, v)`.
+    fn merge<F: FnOnce(V, V) -> V>(&mut self, k: K, v: V, f: F);
+
+    /// Returns the value for `k`, inserting `f()` if there isn't one.
+    fn value_or_insert_with<F: FnOnce() -> V>(&mut self, k: K, f: F) -> &mut V;
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher> Map<K, V> for HashMap<K, V, S> {
+    fn merge<F: FnOnce(V, V) -> V>(&mut self, k: K, v: V, f: F) {
+        match self.entry(k) {
+            hash_map::Entry::Occupied(e) => {
+                let (k, old) = e.remove_entry();
+                self.insert(k, f(old, v));
+            }
+            hash_map::Entry::Vacant(e) => {
+                e.insert(v);
+            }
+        }
+    }
+
+    fn value_or_insert_with<F: FnOnce() -> V>(&mut self, k: K, f: F) -> &mut V {
+        self.entry(k).or_insert_with(f)
+    }
+}
+
+impl<K: Ord, V> Map<K, V> for BTreeMap<K, V> {
+    fn merge<F: FnOnce(V, V) -> V>(&mut self, k: K, v: V, f: F) {
+        match self.entry(k) {
+            btree_map::Entry::Occupied(e) => {
+                let (k, old) = e.remove_entry();
+                self.insert(k, f(old, v));
+            }
+            btree_map::Entry::Vacant(e) => {
+                e.insert(v);
+            }
+        }
+    }
+
+    fn value_or_insert_with<F: FnOnce() -> V>(&mut self, k: K, f: F) -> &mut V {
+        self.entry(k).or_insert_with(f)
+    }
+}
+
+#[cfg(feature = "indexmap")]
+impl<K: Eq + Hash, V, S: BuildHasher> Map<K, V> for indexmap::IndexMap<K, V, S> {
+    fn merge<F: FnOnce(V, V) -> V>(&mut self, k: K, v: V, f: F) {
+        match self.entry(k) {
+            // Move the re-inserted entry back to where it was
+            indexmap::map::Entry::Occupied(e) => {
+                let i = e.index();
+                let (k, old) = e.swap_remove_entry();
+                let (j, _) = self.insert_full(k, f(old, v));
+                self.swap_indices(i, j);
+            }
+            indexmap::map::Entry::Vacant(e) => {
+                e.insert(v);
+            }
+        }
+    }
+
+    fn value_or_insert_with<F: FnOnce() -> V>(&mut self, k: K, f: F) -> &mut V {
+        self.entry(k).or_insert_with(f)
+    }
+}
+
+/// The default value of `V`, used to give the starting value of an
+/// accumulating map entry the same type as its first operand.
+pub fn default_like<V: Default>(_: &V) -> V {
+    V::default()
+}
+
+/// Zips two iterators, panicking if one ends before the other.
+pub struct ZipEq<A, B> {
+    a: A,
+    b: B,
+}
+
+pub fn zip_eq<A: IntoIterator, B: IntoIterator>(a: A, b: B) -> ZipEq<A::IntoIter, B::IntoIter> {
+    ZipEq {
+        a: a.into_iter(),
+        b: b.into_iter(),
+    }
+}
+
+impl<A: Iterator, B: Iterator> Iterator for ZipEq<A, B> {
+    type Item = (A::Item, B::Item);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match (self.a.next(), self.b.next()) {
+            (Some(a), Some(b)) => Some((a, b)),
+            (None, None) => None,
+            _ => panic!("zipped iterators have different lengths"),
+        }
+    }
+}
+
+/// Zips two iterators until both end, filling in for the one that ends first.
+pub struct ZipLongest<A: Iterator, B: Iterator> {
+    a: std::iter::Fuse<A>,
+    b: std::iter::Fuse<B>,
+    fill: (A::Item, B::Item),
+}
+
+pub fn zip_longest<A: IntoIterator, B: IntoIterator>(
+    a: A,
+    b: B,
+    fill_a: A::Item,
+    fill_b: B::Item,
+) -> ZipLongest<A::IntoIter, B::IntoIter> {
+    ZipLongest {
+        a: a.into_iter().fuse(),
+        b: b.into_iter().fuse(),
+        fill: (fill_a, fill_b),
+    }
+}
+
+impl<A: Iterator, B: Iterator> ZipLongest<A, B>
+where
+    A::Item: Clone,
+    B::Item: Clone,
+{
+    /// The item to fill in when zipping this with a longer iterator.
+    pub fn fill(&self) -> (A::Item, B::Item) {
+        self.fill.clone()
+    }
+}
+
+impl<A: Iterator, B: Iterator> Iterator for ZipLongest<A, B>
+where
+    A::Item: Clone,
+    B::Item: Clone,
+{
+    type Item = (A::Item, B::Item);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match (self.a.next(), self.b.next()) {
+            (None, None) => None,
+            (a, b) => Some((
+                a.unwrap_or_else(|| self.fill.0.clone()),
+                b.unwrap_or_else(|| self.fill.1.clone()),
+            )),
+        }
+    }
+}
+
+/// A number that ranges can be stepped over.
+pub trait Number: Copy {
+    /// The number of values `start + i * step` in the range, and the exact
+    /// value to use for the last one, if any.
+    fn count(start: Self, end: Self, step: Self, inclusive: bool) -> (usize, Option<Self>);
+
+    /// `start + i * step`.
+    fn nth(start: Self, step: Self, i: usize) -> Self;
+}
+
+macro_rules! int_number {
+    ($($t:ty)*) => {$(
+        impl Number for $t {
+            fn count(start: Self, end: Self, step: Self, inclusive: bool) -> (usize, Option<Self>) {
+                if step == 0 {
+                    panic!("range step can't be zero");
+                }
+                let (distance, step) = (end as i128 - start as i128, step as i128);
+                let count = if distance == 0 {
+                    inclusive as i128
+                } else if (distance < 0) != (step < 0) {
+                    0
+                } else if inclusive {
+                    distance / step + 1
+                } else {
+                    (distance - step.signum()) / step + 1
+                };
+                (count as usize, None)
+            }
+
+            fn nth(start: Self, step: Self, i: usize) -> Self {
+                (start as i128 + step as i128 * i as i128) as Self
+            }
+        }
+    )*};
+}
+
+int_number!(i8 i16 i32 i64 isize u8 u16 u32 u64 usize);
+
+macro_rules! float_number {
+    ($($t:ty)*) => {$(
+        impl Number for $t {
+            fn count(start: Self, end: Self, step: Self, inclusive: bool) -> (usize, Option<Self>) {
+                if step == 0.0 {
+                    panic!("range step can't be zero");
+                }
+                // Treat a number of steps within rounding error of a whole
+                // number as exact, so that `0.0..=0.3 step 0.1` ends at 0.3.
+                let steps = (end - start) / step;
+                let whole = steps.round();
+                let exact = (steps - whole).abs() <= whole.abs().max(1.0) * Self::EPSILON * 64.0;
+                if steps.is_nan() || whole < 0.0 || (steps < 0.0 && !exact) {
+                    return (0, None);
+                }
+                match (exact, inclusive) {
+                    (true, true) => (whole as usize + 1, Some(end)),
+                    (true, false) => (whole as usize, None),
+                    (false, _) => (steps.floor() as usize + 1, None),
+                }
+            }
+
+            fn nth(start: Self, step: Self, i: usize) -> Self {
+                start + step * i as Self
+            }
+        }
+    )*};
+}
+
+float_number!(f32 f64);
+
+/// A range that can be stepped over.
+pub trait Bounds<T> {
+    fn bounds(self) -> (T, T, bool);
+}
+
+impl<T> Bounds<T> for std::ops::Range<T> {
+    fn bounds(self) -> (T, T, bool) {
+        (self.start, self.end, false)
+    }
+}
+
+impl<T> Bounds<T> for std::ops::RangeInclusive<T> {
+    fn bounds(self) -> (T, T, bool) {
+        let (start, end) = self.into_inner();
+        (start, end, true)
+    }
+}
+
+/// The values `start + i * step` in a range, computed without accumulating
+/// rounding error.
+pub struct Step<T> {
+    start: T,
+    step: T,
+    next: usize,
+    len: usize,
+    last: Option<T>,
+}
+
+pub fn step<T: Number, R: Bounds<T>>(range: R, step: T) -> Step<T> {
+    let (start, end, inclusive) = range.bounds();
+    let (len, last) = T::count(start, end, step, inclusive);
+    Step {
+        start,
+        step,
+        next: 0,
+        len,
+        last,
+    }
+}
+
+/// `n` evenly spaced values from `start` to `end`, inclusive.
+pub fn linspace<T: Float>(start: T, end: T, n: usize) -> Step<T> {
+    let step = if n > 1 {
+        (end - start) / T::from_usize(n - 1)
+    } else {
+        end - start
+    };
+    Step {
+        start,
+        step,
+        next: 0,
+        len: n,
+        last: if n > 1 { Some(end) } else { None },
+    }
+}
+
+/// A floating-point type `linspace` can divide into steps.
+pub trait Float: Number + std::ops::Sub<Output = Self> + std::ops::Div<Output = Self> {
+    fn from_usize(n: usize) -> Self;
+}
+
+impl Float for f32 {
+    fn from_usize(n: usize) -> Self {
+        n as f32
+    }
+}
+
+impl Float for f64 {
+    fn from_usize(n: usize) -> Self {
+        n as f64
+    }
+}
+
+impl<T: Number> Iterator for Step<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.next == self.len {
+            return None;
+        }
+        self.next += 1;
+        match self.last {
+            Some(last) if self.next == self.len => Some(last),
+            _ => Some(T::nth(self.start, self.step, self.next - 1)),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.len - self.next;
+        (len, Some(len))
+    }
+}
+
+impl<T: Number> ExactSizeIterator for Step<T> {}
+
+/// The items of a Python-style slice `source[start:end:step]`, walking
+/// forwards with `F` or backwards with `B`.
+pub enum Slice<F, B> {
+    Forward(F),
+    Backward(B),
+}
+
+/// A source to slice with [`SliceBack::slice`] when it can be walked
+/// backwards, or with [`SliceBuffered::slice`] otherwise. The method call
+/// picks the first one whose bounds hold, since it takes `self` by value.
+pub struct Sliced<I>(Option<I>);
+
+/// Wraps a source to slice.
+pub fn sliced<I: IntoIterator>(source: I) -> Sliced<I> {
+    Sliced(Some(source))
+}
+
+/// Like [`sliced`], for slices written with a negative literal, so that a
+/// source without a known length is rejected at compile time.
+pub fn sliced_sized<I: KnownLength>(source: I) -> Sliced<I> {
+    Sliced(Some(source))
+}
+
+/// A source whose length is known before looping over it.
+#[diagnostic::on_unimplemented(
+    message = "negative slice indices and steps need a source with a known length",
+    label = "`{Self}` doesn't iterate over an `ExactSizeIterator`",
+    note = "slices, `Vec`s and `ExactSizeIterator`s can be sliced from the end"
+)]
+pub trait KnownLength: IntoIterator {}
+
+impl<I: IntoIterator> KnownLength for I where I::IntoIter: ExactSizeIterator {}
+
+type Window<I> = Take<Skip<I>>;
+
+/// Slices a source that can be walked backwards without buffering it.
+pub trait SliceBack {
+    type Iter: Iterator;
+
+    fn slice(self, start: Option<isize>, end: Option<isize>, step: Option<isize>) -> Self::Iter;
+}
+
+impl<I: IntoIterator> SliceBack for Sliced<I>
+where
+    I::IntoIter: DoubleEndedIterator + ExactSizeIterator,
+{
+    type Iter = Slice<StepBy<Window<I::IntoIter>>, StepBy<Rev<Window<I::IntoIter>>>>;
+
+    fn slice(self, start: Option<isize>, end: Option<isize>, step: Option<isize>) -> Self::Iter {
+        let (items, step) = window(self.0.unwrap(), start, end, step);
+        if step > 0 {
+            Slice::Forward(items.step_by(step as usize))
+        } else {
+            Slice::Backward(items.rev().step_by(step.unsigned_abs()))
+        }
+    }
+}
+
+/// Slices any source, collecting the items into a `Vec` to walk them
+/// backwards.
+pub trait SliceBuffered {
+    type Iter: Iterator;
+
+    fn slice(self, start: Option<isize>, end: Option<isize>, step: Option<isize>) -> Self::Iter;
+}
+
+impl<I: IntoIterator> SliceBuffered for &mut Sliced<I> {
+    type Iter = Slice<StepBy<Window<I::IntoIter>>, StepBy<Rev<std::vec::IntoIter<I::Item>>>>;
+
+    fn slice(self, start: Option<isize>, end: Option<isize>, step: Option<isize>) -> Self::Iter {
+        let (items, step) = window(self.0.take().unwrap(), start, end, step);
+        if step > 0 {
+            Slice::Forward(items.step_by(step as usize))
+        } else {
+            let items: Vec<_> = items.collect();
+            Slice::Backward(items.into_iter().rev().step_by(step.unsigned_abs()))
+        }
+    }
+}
+
+/// Narrows `source` to the items of a Python-style slice, in their original
+/// order. Negative indices count from the end, and a negative step walks the
+/// slice backwards, so both need to know the length of the source.
+fn window<I: IntoIterator>(
+    source: I,
+    start: Option<isize>,
+    end: Option<isize>,
+    step: Option<isize>,
+) -> (Window<I::IntoIter>, isize) {
+    let iter = source.into_iter();
+    let step = step.unwrap_or(1);
+    if step == 0 {
+        panic!("slice step can't be zero");
+    }
+    let len = match iter.size_hint() {
+        (lo, Some(hi)) if lo == hi => Some(lo as isize),
+        _ => None,
+    };
+    let unsized_error = || -> isize {
+        panic!("negative slice indices and steps need a source with a known length, like a slice, a `Vec` or an `ExactSizeIterator`")
+    };
+    let index = |i: isize| if i < 0 { i + len.unwrap_or_else(unsized_error) } else { i };
+    if step > 0 {
+        let start = start.map_or(0, index).max(0);
+        let count = end.map_or(usize::MAX, |end| (index(end) - start).max(0) as usize);
+        (iter.skip(start as usize).take(count), step)
+    } else {
+        let len = len.unwrap_or_else(unsized_error);
+        let start = start.map_or(len - 1, index).min(len - 1);
+        let end = end.map_or(-1, |end| index(end).max(-1));
+        let count = (start - end).max(0) as usize;
+        (iter.skip((end + 1) as usize).take(count), step)
+    }
+}
+
+impl<T, F: Iterator<Item = T>, B: Iterator<Item = T>> Iterator for Slice<F, B> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match self {
+            Slice::Forward(iter) => iter.next(),
+            Slice::Backward(iter) => iter.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            Slice::Forward(iter) => iter.size_hint(),
+            Slice::Backward(iter) => iter.size_hint(),
+        }
+    }
+}
+
+/// A stream whose items are sent by a future, like a generator function.
+#[cfg(feature = "futures")]
+pub struct Generator<T, F> {
+    slot: Arc<Mutex<Option<T>>>,
+    future: Option<Pin<Box<F>>>,
+}
+
+/// Creates a [`Generator`] from a function that sends its items through the
+/// given [`Sender`].
+#[cfg(feature = "futures")]
+pub fn generator<T, F: Future<Output = ()>>(f: impl FnOnce(Sender<T>) -> F) -> Generator<T, F> {
+    let slot = Arc::new(Mutex::new(None));
+    let future = f(Sender(slot.clone()));
+    Generator {
+        slot,
+        future: Some(Box::pin(future)),
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<T, F: Future<Output = ()>> futures::Stream for Generator<T, F> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let future = match self.future.as_mut() {
+            Some(future) => future,
+            None => return Poll::Ready(None),
+        };
+        let done = future.as_mut().poll(cx).is_ready();
+        if done {
+            self.future = None;
+        }
+        match self.slot.lock().unwrap().take() {
+            Some(item) => Poll::Ready(Some(item)),
+            None if done => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Sends items to a [`Generator`].
+#[cfg(feature = "futures")]
+pub struct Sender<T>(Arc<Mutex<Option<T>>>);
+
+#[cfg(feature = "futures")]
+impl<T> Sender<T> {
+    /// Sends `item`, and waits until the stream has returned it.
+    pub fn send(&self, item: T) -> Sending<'_, T> {
+        *self.0.lock().unwrap() = Some(item);
+        Sending(&self.0)
+    }
+}
+
+#[cfg(feature = "futures")]
+pub struct Sending<'a, T>(&'a Mutex<Option<T>>);
+
+#[cfg(feature = "futures")]
+impl<T> Future for Sending<'_, T> {
+    type Output = ();
+
+    // The stream takes the item as soon as this returns `Pending`, and polls
+    // again on the next call to `poll_next`, so there's no need to wake.
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        if self.0.lock().unwrap().is_some() {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
@@ -3,15 +3,170 @@
 //! The structure is consistent with Python's [list and dictionary comprehensions](https://docs.python.org/3/reference/expressions.html#displays-for-lists-sets-and-dictionaries).
 //!
 //! - If the loop body (everything before the first `for`) contains two
-//! expressions separated by `:`, it will be treated as a hash map comprehension.
+//!   expressions separated by `:`, it will be treated as a hash map comprehension.
 //!
 //! - If the loop body ends with a `;`, it will be a statement comprehension
-//! (it won't evaluate to a collection).
+//!   (it won't evaluate to a collection).
+//!
+//! - If the comprehension starts with `set;`, it will be a hash set comprehension.
+//!   (Rust macros can't tell `c!{...}` from `c![...]`, so the braces alone
+//!   aren't enough.)
+//!
+//! - If the comprehension starts with `ordered;`, map and set comprehensions
+//!   will keep their elements in loop order (using [`IndexMap`] and [`IndexSet`]).
+//!   This requires the `indexmap` feature.
+//!
+//! - If the comprehension starts with `with_hasher(h);`, map and set
+//!   comprehensions will be built with the [`BuildHasher`](std::hash::BuildHasher)
+//!   `h` instead of the default [`RandomState`](std::collections::hash_map::RandomState).
+//!   A hasher that implements [`Default`] can also be named in the collection type,
+//!   e.g. `HashMap<_, _, S>;`.
+//!
+//! - If the comprehension starts with `with_capacity(n);`, space for `n` elements
+//!   will be reserved before the loops run. Vector, map and set comprehensions
+//!   with a single `for` and no `if` reserve space for the iterator's
+//!   [`size_hint`](Iterator::size_hint) automatically.
+//!
+//! - If the comprehension starts with `on_duplicate(policy);`, a map comprehension
+//!   will handle keys that are produced more than once according to `policy`:
+//!   `last` (the default) keeps the last value, `first` keeps the first value
+//!   without evaluating the later ones, `panic` panics, and `error` makes the
+//!   comprehension return a [`Result`] with a [`DuplicateKey`] naming the key.
+//!   `panic` and `error` require the key type to implement [`Debug`](std::fmt::Debug).
+//!
+//! - If the comprehension starts with `strict;`, items that don't match the
+//!   pattern of their `for` loop cause a panic instead of being skipped.
+//!
+//! - If the comprehension starts with `zip(mode);`, `zip` clauses stop at the
+//!   end of their shortest iterator (`shortest`, the default), panic if their
+//!   iterators have different lengths (`strict`), or continue to the end of
+//!   their longest iterator (`longest(fill, ...)`, with one fill value per
+//!   iterator for the ones that end early).
+//!
+//! - If the value of a map comprehension starts with a compound assignment
+//!   operator (`k: += v`), values with the same key are combined with that
+//!   operator instead of replacing each other. Like a Python `defaultdict`, each
+//!   key starts from the [`Default`] value, so `k: -= 1` counts down from zero.
+//!   `k: merge v with f` keeps the first value for a key and combines the later
+//!   ones into it with `f(old, new)`. Both also work with
+//!   [`BTreeMap`](std::collections::BTreeMap) types and `into`.
+//!
+//! - If the value of a map comprehension starts with `group` (`k: group v`), the
+//!   values with the same key are collected into a group, a [`Vec`] by default.
+//!   `k: group v as T` collects them into `T` (any collection implementing
+//!   [`Default`] and [`Extend`]) instead, and maps named by type or `into` use
+//!   their own value type. The last `as` names the collection, so a cast at the
+//!   end of `v` needs the collection type after it, like
+//!   `k: group x as f64 as Vec<_>`.
+//!
+//! - If the value of a map comprehension is another `k: v` pair (`a: b: v`,
+//!   to any depth), the comprehension builds nested maps. Inner maps of the same
+//!   kind are created as they're needed (using [`Default`] if the map type was
+//!   given), and the innermost value can use any of the forms above.
+//!
+//! - If the comprehension starts with a type followed by `;`, the result will be
+//!   collected into that type using [`Default`] and [`Extend`]. Use `_` to infer
+//!   the type from context. Boxed slices (`Box<[T]>`, `Rc<[T]>`, `Arc<[T]>`) are
+//!   built from a [`Vec`].
+//!
+//! - If the comprehension starts with an array type (`[T; N];`), the result will
+//!   be an array built without allocating. The number of elements is only known
+//!   at run time, so producing more or fewer than `N` elements panics.
+//!
+//! - If the comprehension starts with `into out;`, the elements will be added to
+//!   the existing collection `out` (or `*out`, if it's a mutable reference) using
+//!   [`Extend`], and the comprehension won't evaluate to anything.
+//!
+//! - If the comprehension starts with `in c;`, the vector, [`VecDeque`](std::collections::VecDeque)
+//!   or map `c` will be updated in place. The `for` clause has no `in ...`, since it
+//!   iterates over `c` itself, and it may only be followed by `if` clauses. Its
+//!   pattern matches a reference to each element (or a tuple of references to each
+//!   key and value). Elements that don't pass the `if` clauses are removed, and the
+//!   rest are replaced with the value of the loop body.
+//!
+//! - If the comprehension starts with `buffered(n);` inside an `async` block or
+//!   function, the loop body (or the value of a `k: v` body) must be a future.
+//!   Up to `n` of the futures run at once, and their outputs are collected in loop
+//!   order into any of the collections above. `buffer_unordered(n);` collects them
+//!   in the order they finish instead. This requires the `futures` feature.
+//!
+//! - If the comprehension starts with `par;`, the outermost loop runs on a
+//!   [rayon](https://docs.rs/rayon/1) thread pool, and the rest of the loops and
+//!   `if` clauses run sequentially inside each of its iterations. Vectors keep
+//!   their loop order, and maps, sets and other collections implementing
+//!   `FromParallelIterator` can also be collected. Statement bodies run in no
+//!   particular order. This requires the `rayon` feature.
+//!
+//! - If the comprehension starts with `iter;`, it will be a lazy iterator
+//!   (`impl Iterator`) instead of a collection, like a Python generator
+//!   expression. Nothing is allocated, and the loops only run as items are
+//!   requested. Map comprehensions produce `(k, v)` pairs. Inner loops are `move`
+//!   closures, so they take copies of the earlier loop variables; other values they
+//!   use that aren't [`Copy`] should be borrowed first (`let v = &v;`). `move iter;`
+//!   also moves captured values into the outermost loop, so the iterator can be
+//!   returned from a function.
+//!
+//! - If the comprehension starts with `stream;`, it will be a lazy
+//!   [`Stream`](https://docs.rs/futures/0.3/futures/stream/trait.Stream.html)
+//!   instead of a collection. The loops run in an `async move` block, so the body
+//!   and `if` clauses can use `.await`, and `for await` clauses can read from other
+//!   streams. Map comprehensions produce `(k, v)` pairs. This requires the
+//!   `futures` feature.
+//!
+//! - If the comprehension starts with `join(sep, prefix, suffix);`, the
+//!   elements will be written into a single [`String`] using their [`Display`](std::fmt::Display)
+//!   implementations. `prefix` and `suffix` are optional, and so is `sep`
+//!   (`join;`).
+//!
+//! - `display(sep, prefix, suffix);` works the same way, but returns a lazy value
+//!   implementing [`Display`](std::fmt::Display) instead. The loops run each time
+//!   the value is formatted, and any flags (width, precision, etc.) are applied to
+//!   each element.
 //!
 //! - Otherwise it's a vector comprehension.
 //!
 //! Comprehensions consist of a body followed by a `for ... in ...` expression,
 //! followed by any combination of `for ... in ...` or `if ...` expressions.
+//! Items that don't match the pattern of a `for` loop (like `Some(x)`) are
+//! skipped. A comprehension can also use these clauses:
+//!
+//! - `for x in xs zip y in ys` loops over `xs` and `ys` in lockstep instead of
+//!   nesting, and more iterators can be added with more `zip` clauses. The
+//!   outermost loop of a `par;` comprehension can't be zipped.
+//!
+//! - `for x in a..b step s` loops over `a`, `a + s`, `a + 2 * s`, ... up to `b`
+//!   (or through `b` with `a..=b`), counting down if `s` is negative. It works
+//!   with integers and floats; float values are computed from `a` and `s` each
+//!   time, so rounding errors don't add up. `for x in linspace(a, b, n)` loops
+//!   over `n` evenly spaced floats from `a` to `b`, inclusive.
+//!
+//! - `for x in xs[1:-1:2]` slices the source like in Python, skipping the first
+//!   item and then taking every other one, stopping before the last. Negative
+//!   indices count from the end and a negative step walks backwards, which needs
+//!   a source with a known length, like a slice, a `Vec` or an
+//!   [`ExactSizeIterator`]. Walking backwards collects the items into a `Vec`
+//!   first, unless the source is also a [`DoubleEndedIterator`]. Like in a
+//!   path, a name after `::` isn't a slice bound, so write `xs[a: :b]` rather
+//!   than `xs[a::b]`.
+//!
+//! - `if let ... = ...` skips values that don't match its pattern, and its
+//!   bindings can be used by the clauses after it and the body.
+//!
+//! - `let ... = ...` computes a value once for the clauses after it and the
+//!   body, like an assignment expression in a Python comprehension.
+//!
+//! - `while ...` ends the loop it's in as soon as its condition is false, and
+//!   `until ...` (or `break if ...`) ends the whole comprehension as soon as its
+//!   condition is true, so both can be used with infinite iterators. They aren't
+//!   supported by `iter;`, `buffered(n);` or `par;` comprehensions.
+//!
+//! - With the `futures` feature, `for await ... in ...` loops over a
+//!   [`Stream`](https://docs.rs/futures/0.3/futures/stream/trait.Stream.html)
+//!   inside an `async` block or function. It isn't supported by `iter;`,
+//!   `buffered(n);` or `par;` comprehensions.
+//!
+//! `zip`, `step` and `until` only start a clause after a complete expression, so
+//! they still work as names, like the [`std::iter::zip`] function.
 //!
 //! The `for ... in ...` and `if ...` expressions are nested left-to-right, so
 //! ```
@@ -61,6 +216,98 @@
 //! [-2, -1, 0, -6, -3, 0, 3, 6, -10, -5, 0, 5, 10, -14, -7, 0, 7, 14, -18, -9, 0, 9, 18]
 //! ```
 //!
+//! - Filtering and binding with `if let`:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! # use std::collections::HashMap;
+//! let ids = HashMap::from([("ann", "7"), ("bob", "x"), ("cat", "12")]);
+//! let v = c![
+//!     (name, id) for name in ["ann", "bob", "dan", "cat"]
+//!     if let Some(s) = ids.get(name)
+//!     if let Ok(id) = s.parse::<u32>()
+//! ];
+//! println!("{:?}", v);
+//! ```
+//! ```text
+//! [("ann", 7), ("cat", 12)]
+//! ```
+//!
+//! - Reusing a value with `let`:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let v = c![(x, y) for x in 1..=10 let y = x * x % 7 if y > 1];
+//! println!("{:?}", v);
+//! ```
+//! ```text
+//! [(2, 4), (3, 2), (4, 2), (5, 4), (9, 4), (10, 2)]
+//! ```
+//!
+//! - Skipping items with refutable patterns:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let results = ["1", "two", "3"].map(str::parse::<i32>);
+//! let v = c![x for Ok(x) in results];
+//! println!("{:?}", v);
+//! ```
+//! ```text
+//! [1, 3]
+//! ```
+//!
+//! - Looping over several iterators in lockstep:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let names = ["ann", "bob", "cat"];
+//! let ages = [31, 17];
+//! let v = c![format!("{} {}", name, age) for name in names zip age in ages];
+//! let w = c![zip(longest("?", 0)); (name, age) for name in names zip age in ages];
+//! println!("{:?}\n{:?}", v, w);
+//! ```
+//! ```text
+//! ["ann 31", "bob 17"]
+//! [("ann", 31), ("bob", 17), ("cat", 0)]
+//! ```
+//!
+//! - Stepped ranges and evenly spaced values:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let v = c![x for x in 10..0 step -3];
+//! let w = c![x for x in 0.0..=1.0 step 0.25];
+//! let z = c![x for x in linspace(0.0, 1.0, 3)];
+//! println!("{:?} {:?} {:?}", v, w, z);
+//! ```
+//! ```text
+//! [10, 7, 4, 1] [0.0, 0.25, 0.5, 0.75, 1.0] [0.0, 0.5, 1.0]
+//! ```
+//!
+//! - Python-style slices:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let v = [1, 2, 3, 4, 5, 6];
+//! let w = c![x for x in v[1:-1:2]];
+//! let z = c![x for x in v[::-2]];
+//! println!("{:?} {:?}", w, z);
+//! ```
+//! ```text
+//! [2, 4] [6, 4, 2]
+//! ```
+//!
+//! - Ending loops early with `while` and `until`:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let v = c![(x, y) for x in 1.. until x > 3 for y in 1.. while y * y <= x * 4];
+//! println!("{:?}", v);
+//! ```
+//! ```text
+//! [(1, 1), (1, 2), (2, 1), (2, 2), (3, 1), (3, 2), (3, 3)]
+//! ```
+//!
 //! ## Hash Maps
 //!
 //! - A simple hash map comprehension:
@@ -85,6 +332,286 @@
 //! {"[3|C]": "CCC", "[3|B]": "BBB", "[3|A]": "AAA", "[2|B]": "BB", "[1|C]": "C", "[1|A]": "A", "[1|B]": "B", "[2|C]": "CC", "[2|A]": "AA"}
 //! ```
 //!
+//! - Counting and combining values by key:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let words = ["apple", "avocado", "banana", "cherry"];
+//! let counts = c!{w.chars().next().unwrap(): += 1 for w in words};
+//! let longest = c!{w.chars().next().unwrap(): merge w with |a, b| if b.len() > a.len() { b } else { a } for w in words};
+//! println!("{:?} {:?}", counts, longest);
+//! ```
+//! ```text
+//! {'c': 1, 'b': 1, 'a': 2} {'c': "cherry", 'b': "banana", 'a': "avocado"}
+//! ```
+//!
+//! - Grouping values by key:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let words = ["apple", "avocado", "banana", "cherry"];
+//! let groups = c!{w.chars().next().unwrap(): group w for w in words};
+//! println!("{:?}", groups);
+//! ```
+//! ```text
+//! {'b': ["banana"], 'a': ["apple", "avocado"], 'c': ["cherry"]}
+//! ```
+//!
+//! - Nested maps:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let orders = [("alice", "tea", 2), ("bob", "coffee", 1), ("alice", "tea", 1)];
+//! let m = c!{who: what: += n for (who, what, n) in orders};
+//! println!("{:?}", m);
+//! ```
+//! ```text
+//! {"bob": {"coffee": 1}, "alice": {"tea": 3}}
+//! ```
+//!
+//! - Rejecting duplicate keys:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let words = ["apple", "avocado", "banana"];
+//! let m = c!{on_duplicate(error); w.chars().next().unwrap(): w for w in words};
+//! println!("{}", m.unwrap_err());
+//! ```
+//! ```text
+//! duplicate key in map comprehension: 'a'
+//! ```
+//!
+//! ## Hash Sets
+//!
+//! - A simple hash set comprehension:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let s = c!{set; x % 3 for x in 1..=10};
+//! println!("{:?}", s);
+//! ```
+//! ```text
+//! {1, 0, 2}
+//! ```
+//!
+//! ## Ordered Maps and Sets
+//!
+//! - An insertion-ordered hash map comprehension (requires the `indexmap` feature):
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! # #[cfg(feature = "indexmap")] {
+//! let m = c!{ordered; x: x * (1 + x) for x in 1..=10};
+//! println!("{:?}", m);
+//! # }
+//! ```
+//! ```text
+//! {1: 2, 2: 6, 3: 12, 4: 20, 5: 30, 6: 42, 7: 56, 8: 72, 9: 90, 10: 110}
+//! ```
+//!
+//! ## Iterators
+//!
+//! - A lazy iterator:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let total: i32 = c![iter; x * y for x in 1..=3 for y in x..=3 if x != y].sum();
+//! println!("{}", total);
+//! ```
+//! ```text
+//! 11
+//! ```
+//!
+//! ## Arrays
+//!
+//! - A lookup table:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let squares = c![[u16; 8]; x * x for x in 0..8];
+//! println!("{:?}", squares);
+//! ```
+//! ```text
+//! [0, 1, 4, 9, 16, 25, 36, 49]
+//! ```
+//!
+//! ## Strings
+//!
+//! - Joining elements with a separator:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let s = c![join(", ", "[", "]"); x * x for x in 1..=5];
+//! println!("{}", s);
+//! ```
+//! ```text
+//! [1, 4, 9, 16, 25]
+//! ```
+//!
+//! - Formatting lazily, without allocating:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let v = vec![1.0, 2.5, 4.25];
+//! println!("{:.2}", c![display(" | "); x / 2.0 for x in &v]);
+//! ```
+//! ```text
+//! 0.50 | 1.25 | 2.12
+//! ```
+//!
+//! ## Custom Hashers
+//!
+//! - A hash map comprehension with a fixed-seed hasher:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! use std::collections::hash_map::DefaultHasher;
+//! use std::hash::BuildHasherDefault;
+//!
+//! let m = c!{with_hasher(BuildHasherDefault::<DefaultHasher>::default()); x: x * x for x in 1..=5};
+//! println!("{:?}", m);
+//! ```
+//! ```text
+//! {1: 1, 2: 4, 4: 16, 3: 9, 5: 25}
+//! ```
+//!
+//! ## Existing Collections
+//!
+//! - Appending to a vector:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let mut v = vec![0, 1];
+//! c![into v; x * x for x in 2..=5];
+//! println!("{:?}", v);
+//! ```
+//! ```text
+//! [0, 1, 4, 9, 16, 25]
+//! ```
+//!
+//! - Updating a vector in place:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! let mut v = vec![3, -1, 4, -1, 5];
+//! c!(in v; x * 2 for &x if x > 0);
+//! println!("{:?}", v);
+//! ```
+//! ```text
+//! [6, 8, 10]
+//! ```
+//!
+//! ## Other Collections
+//!
+//! - A sorted map:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! use std::collections::BTreeMap;
+//!
+//! let m = c!{BTreeMap<_, _>; x: x * (1 + x) for x in 1..=5};
+//! println!("{:?}", m);
+//! ```
+//! ```text
+//! {1: 2, 2: 6, 3: 12, 4: 20, 5: 30}
+//! ```
+//!
+//! - Inferring the collection from context:
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! use std::collections::BTreeSet;
+//!
+//! let s: BTreeSet<_> = c![_; x % 3 for x in 1..=10];
+//! println!("{:?}", s);
+//! ```
+//! ```text
+//! {0, 1, 2}
+//! ```
+//!
+//! ## Streams
+//!
+//! - Draining a stream (requires the `futures` feature):
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! # #[cfg(feature = "futures")] {
+//! use futures::stream;
+//!
+//! # futures::executor::block_on(
+//! async {
+//!     let v = c![x * x for await x in stream::iter(1..=5) if x % 2 == 1];
+//!     println!("{:?}", v);
+//! }
+//! # );
+//! # }
+//! ```
+//! ```text
+//! [1, 9, 25]
+//! ```
+//!
+//! - Producing a stream (requires the `futures` feature):
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! # #[cfg(feature = "futures")] {
+//! use futures::{Stream, StreamExt};
+//!
+//! async fn is_prime(n: u32) -> bool {
+//!     (2..n).all(|d| n % d != 0)
+//! }
+//!
+//! fn primes(limit: u32) -> impl Stream<Item = u32> {
+//!     c![stream; n for n in 2..limit if is_prime(n).await]
+//! }
+//!
+//! # futures::executor::block_on(
+//! async {
+//!     println!("{:?}", primes(20).collect::<Vec<_>>().await);
+//! }
+//! # );
+//! # }
+//! ```
+//! ```text
+//! [2, 3, 5, 7, 11, 13, 17, 19]
+//! ```
+//!
+//! - Running futures concurrently (requires the `futures` feature):
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! # #[cfg(feature = "futures")] {
+//! async fn fetch(id: u32) -> String {
+//!     format!("item {}", id)
+//! }
+//!
+//! # futures::executor::block_on(
+//! async {
+//!     let v = c![buffered(4); fetch(id) for id in 1..=3];
+//!     println!("{:?}", v);
+//! }
+//! # );
+//! # }
+//! ```
+//! ```text
+//! ["item 1", "item 2", "item 3"]
+//! ```
+//!
+//! ## Parallel
+//!
+//! - A parallel vector comprehension (requires the `rayon` feature):
+//! ```
+//! # extern crate comprende;
+//! # use comprende::c;
+//! # #[cfg(feature = "rayon")] {
+//! let v = c![par; x * y for x in 1..=3 for y in 0..x if y % 2 == 0];
+//! println!("{:?}", v);
+//! # }
+//! ```
+//! ```text
+//! [0, 0, 0, 6]
+//! ```
+//!
 //! ## Statements
 //!
 //! - A simple statement comprehension:
@@ -98,104 +625,1162 @@
 //! ```text
 //! 3628800
 //! ```
+//!
+//! [`IndexMap`]: https://docs.rs/indexmap/2/indexmap/map/struct.IndexMap.html
+//! [`IndexSet`]: https://docs.rs/indexmap/2/indexmap/set/struct.IndexSet.html
 
 extern crate clean_macro_docs;
 use clean_macro_docs::clean_docs;
 
+#[doc(hidden)]
+pub mod __private;
+
+/// The error returned by a map comprehension with `on_duplicate(error);`
+/// when a key is produced more than once. Holds the duplicate key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DuplicateKey<K>(pub K);
+
+impl<K: std::fmt::Debug> std::fmt::Display for DuplicateKey<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "duplicate key in map comprehension: {:?}", self.0)
+    }
+}
+
+impl<K: std::fmt::Debug> std::error::Error for DuplicateKey<K> {}
+
 #[clean_docs]
 #[macro_export]
 macro_rules! c {
     // Preprocess the loop body expression.
+    // The first group carries the comprehension's options (see @option)
+    // through to @construct[0].
 
-    // Replace `:` with `=>` and proceed to @preprocess[1]
-    (@preprocess[0] {: $($ts:tt)*} {$($procd_ts:tt)*}) =>
-        { c!(@preprocess[1] {$($ts)*} {$($procd_ts)* =>}) };
+    // Copy closure parameters up to the closing `|`
+    (@preprocess[0] @params $opts:tt {| $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[0] $opts {$($ts)*} {$($procd_ts)* |}) };
+    (@preprocess[0] @params $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[0] @params $opts {$($ts)*} {$($procd_ts)* $t}) };
+    // ERROR: No closing `|`
+    (@preprocess[0] @params $opts:tt {} {$($procd_ts:tt)*}) =>
+        { compile_error!("Unclosed closure parameters") };
+
+    // `;` followed by `for` ends a statement loop body, continue to next token
+    (@preprocess[0] $opts:tt {; for $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[0] $opts {for $($ts)*} {$($procd_ts)* ;}) };
+
+    // Any other `;` ends an option, proceed to @option
+    (@preprocess[0] $opts:tt {; $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@option $opts {$($procd_ts)*} {$($ts)*}) };
+
+    // Replace `:` with `=>`, but keep any `:` in the parameters of a
+    // closure value
+    (@preprocess[0] $opts:tt {: | $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[0] @params $opts {$($ts)*} {$($procd_ts)* => |}) };
+    (@preprocess[0] $opts:tt {: move | $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[0] @params $opts {$($ts)*} {$($procd_ts)* => move |}) };
+    (@preprocess[0] $opts:tt {: $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[0] $opts {$($ts)*} {$($procd_ts)* =>}) };
 
     // Reached end of the loop body expression, proceed to @preprocess[1]
-    (@preprocess[0] {for $($ts:tt)*} {$($procd_ts:tt)*}) =>
-        { c!(@preprocess[1] {for $($ts)*} {$($procd_ts)*}) };
+    (@preprocess[0] $opts:tt {for $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[1] $opts {for $($ts)*} {$($procd_ts)*} []) };
 
     // Continue to next token
-    (@preprocess[0] {$t:tt $($ts:tt)*} {$($procd_ts:tt)*}) =>
-        { c!(@preprocess[0] {$($ts)*} {$($procd_ts)* $t}) };
+    (@preprocess[0] $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[0] $opts {$($ts)*} {$($procd_ts)* $t}) };
 
     // ERROR: No `for`
-    (@preprocess[0] {} {$($procd_ts:tt)*}) =>
+    (@preprocess[0] $opts:tt {} {$($procd_ts:tt)*}) =>
         { compile_error!("Comprehension must contain at least one `for ... in ...` expression") };
 
 
-    // Preprocess the loop and conditional components.
-    // Replaces instances of `for` with `, for` and `if` with `, if`.
+    // Parse an option and return to @preprocess[0] for the rest of the
+    // comprehension. The options group holds the output collection,
+    // the hasher, the capacity, the duplicate-key policy and how the loop
+    // bodies are run (concurrently or in parallel).
+
+    (@option {$coll:tt $hasher:tt [] $dup:tt $conc:tt} {with_capacity($n:expr)} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {$coll $hasher [$n] $dup $conc} {$($ts)*} {}) };
+    // ERROR: More than one capacity
+    (@option {$coll:tt $hasher:tt [$($n:tt)+] $dup:tt $conc:tt} {with_capacity $($o:tt)*} {$($ts:tt)*}) =>
+        { compile_error!("Comprehension can only have one capacity") };
+    (@option {$coll:tt [] $cap:tt $dup:tt $conc:tt} {with_hasher($h:expr)} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {$coll [$h] $cap $dup $conc} {$($ts)*} {}) };
+    // ERROR: More than one hasher
+    (@option {$coll:tt [$($h:tt)+] $cap:tt $dup:tt $conc:tt} {with_hasher $($o:tt)*} {$($ts:tt)*}) =>
+        { compile_error!("Comprehension can only have one hasher") };
+    (@option {$coll:tt $hasher:tt $cap:tt [] $conc:tt} {on_duplicate($policy:ident)} {$($ts:tt)*}) =>
+        { c!(@policy {$coll $hasher $cap} $conc $policy {$($ts)*}) };
+    // ERROR: More than one duplicate-key policy
+    (@option {$coll:tt $hasher:tt $cap:tt [$($d:tt)+] $conc:tt} {on_duplicate $($o:tt)*} {$($ts:tt)*}) =>
+        { compile_error!("Comprehension can only have one duplicate-key policy") };
+    (@option {$coll:tt $hasher:tt $cap:tt $dup:tt []} {buffered($n:expr)} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {$coll $hasher $cap $dup [buffered $n]} {$($ts)*} {}) };
+    (@option {$coll:tt $hasher:tt $cap:tt $dup:tt []} {buffer_unordered($n:expr)} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {$coll $hasher $cap $dup [buffer_unordered $n]} {$($ts)*} {}) };
+    (@option {$coll:tt $hasher:tt $cap:tt $dup:tt []} {par} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {$coll $hasher $cap $dup [par]} {$($ts)*} {}) };
+    // ERROR: More than one of `buffered`, `buffer_unordered` and `par`
+    (@option {$coll:tt $hasher:tt $cap:tt $dup:tt [$($c:tt)+]} {buffered $($o:tt)*} {$($ts:tt)*}) =>
+        { compile_error!("Comprehension can only have one of `buffered`, `buffer_unordered` and `par`") };
+    (@option {$coll:tt $hasher:tt $cap:tt $dup:tt [$($c:tt)+]} {buffer_unordered $($o:tt)*} {$($ts:tt)*}) =>
+        { compile_error!("Comprehension can only have one of `buffered`, `buffer_unordered` and `par`") };
+    (@option {$coll:tt $hasher:tt $cap:tt $dup:tt [$($c:tt)+]} {par} {$($ts:tt)*}) =>
+        { compile_error!("Comprehension can only have one of `buffered`, `buffer_unordered` and `par`") };
+    (@option $opts:tt {strict} {$($ts:tt)*}) =>
+        { c!(@strict $opts {$($ts)*} {}) };
+    (@option $opts:tt {zip(shortest)} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] $opts {$($ts)*} {}) };
+    (@option $opts:tt {zip(strict)} {$($ts:tt)*}) =>
+        { c!(@zip[0] $opts [@strict []] [] {$($ts)*} {}) };
+    (@option $opts:tt {zip(longest($($fill:expr),+ $(,)?))} {$($ts:tt)*}) =>
+        { c!(@zip[0] $opts [@longest [$($fill),+]] [] {$($ts)*} {}) };
+    (@option $opts:tt {zip $($o:tt)*} {$($ts:tt)*}) =>
+        { compile_error!(concat!("Invalid zip mode `", stringify!($($o)*), "`, expected `(shortest)`, `(strict)` or `(longest(fill, ...))`")) };
+    // `set` and `ordered` can be combined in either order
+    (@option {{ordered} $hasher:tt $cap:tt $dup:tt $conc:tt} {set} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{ordered set} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    (@option {{set} $hasher:tt $cap:tt $dup:tt $conc:tt} {ordered} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{ordered set} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    // ERROR: More than one output collection
+    (@option {{$($coll:tt)+} $hasher:tt $cap:tt $dup:tt $conc:tt} {$($o:tt)*} {$($ts:tt)*}) =>
+        { compile_error!("Comprehension can only have one output collection") };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {set} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{set} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {ordered} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{ordered} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {iter} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{iter []} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {move iter} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{iter [move]} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {stream} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{stream [move]} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {in $c:expr} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{in [$c]} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {into $out:expr} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{into [$out]} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {join} {$($ts:tt)*}) =>
+        { c!(@option {{} $hasher $cap $dup $conc} {join("", "", "")} {$($ts)*}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {join($sep:expr)} {$($ts:tt)*}) =>
+        { c!(@option {{} $hasher $cap $dup $conc} {join($sep, "", "")} {$($ts)*}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {join($sep:expr, $prefix:expr, $suffix:expr)} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{join [$sep] [$prefix] [$suffix]} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {display} {$($ts:tt)*}) =>
+        { c!(@option {{} $hasher $cap $dup $conc} {display("", "", "")} {$($ts)*}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {display($sep:expr)} {$($ts:tt)*}) =>
+        { c!(@option {{} $hasher $cap $dup $conc} {display($sep, "", "")} {$($ts)*}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {display($sep:expr, $prefix:expr, $suffix:expr)} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{display [$sep] [$prefix] [$suffix]} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {[$t:ty; $n:expr]} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{array $t [$n]} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    // Boxed slices have no `Extend` implementation
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {$($ptr:ident)::+ <[$t:ty]>} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{slice [$($ptr)::+] $t} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    (@option {{} $hasher:tt $cap:tt $dup:tt $conc:tt} {$t:ty} {$($ts:tt)*}) =>
+        { c!(@preprocess[0] {{type $t} $hasher $cap $dup $conc} {$($ts)*} {}) };
+    // ERROR: Unknown option
+    (@option $opts:tt {$($o:tt)*} {$($ts:tt)*}) =>
+        { compile_error!(concat!("Invalid comprehension option `", stringify!($($o)*), "`")) };
+
+
+    // Mark every `for` clause as strict, continue to @preprocess[0]
+    (@strict $opts:tt {for await @strict $($ts:tt)*} $procd:tt) =>
+        { compile_error!("Comprehension can only be `strict` once") };
+    (@strict $opts:tt {for @strict $($ts:tt)*} $procd:tt) =>
+        { compile_error!("Comprehension can only be `strict` once") };
+    (@strict $opts:tt {for await $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@strict $opts {$($ts)*} {$($procd_ts)* for await @strict}) };
+    (@strict $opts:tt {for $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@strict $opts {$($ts)*} {$($procd_ts)* for @strict}) };
+    (@strict $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@strict $opts {$($ts)*} {$($procd_ts)* $t}) };
+    (@strict $opts:tt {} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[0] $opts {$($procd_ts)*} {}) };
+
+
+    // Mark every `zip` clause with the zip mode, continue to @preprocess[0].
+    // The second group becomes `[for]` once the loop body has been copied,
+    // so that a `zip` in the body is left alone.
+    (@zip[0] $opts:tt $mode:tt [] {for $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@zip[0] $opts $mode [for] {$($ts)*} {$($procd_ts)* for}) };
+    (@zip[0] $opts:tt $mode:tt $body:tt {. zip $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@zip[0] $opts $mode $body {$($ts)*} {$($procd_ts)* . zip}) };
+    (@zip[0] $opts:tt $mode:tt $body:tt {:: zip $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@zip[0] $opts $mode $body {$($ts)*} {$($procd_ts)* :: zip}) };
+    (@zip[0] $opts:tt $mode:tt [for] {zip @ $($ts:tt)*} $procd:tt) =>
+        { compile_error!("Comprehension can only have one zip mode") };
+    (@zip[0] $opts:tt [$($mode:tt)*] [for] {zip $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@zip[0] $opts [$($mode)*] [for] {$($ts)*} {$($procd_ts)* zip $($mode)*}) };
+    (@zip[0] $opts:tt $mode:tt $body:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@zip[0] $opts $mode $body {$($ts)*} {$($procd_ts)* $t}) };
+    (@zip[0] $opts:tt $mode:tt $body:tt {} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[0] $opts {$($procd_ts)*} {}) };
+
+
+    // Validate a duplicate-key policy.
+    (@policy {$($opts:tt)*} $conc:tt last {$($ts:tt)*}) => { c!(@preprocess[0] {$($opts)* [last] $conc} {$($ts)*} {}) };
+    (@policy {$($opts:tt)*} $conc:tt first {$($ts:tt)*}) => { c!(@preprocess[0] {$($opts)* [first] $conc} {$($ts)*} {}) };
+    (@policy {$($opts:tt)*} $conc:tt panic {$($ts:tt)*}) => { c!(@preprocess[0] {$($opts)* [panic] $conc} {$($ts)*} {}) };
+    (@policy {$($opts:tt)*} $conc:tt error {$($ts:tt)*}) => { c!(@preprocess[0] {$($opts)* [error] $conc} {$($ts)*} {}) };
+    (@policy $opts:tt $conc:tt $policy:ident $ts:tt) =>
+        { compile_error!(concat!("Invalid duplicate-key policy `", stringify!($policy), "`, expected `last`, `first`, `panic` or `error`")) };
+
+
+    // Preprocess the loop and conditional components.
+    // Puts a `,` before each `for`, `if`, `let`, `while`, `until`, `zip` and
+    // `step` clause.
     // This allows us to match with more specific fragments, such as
     // expr and stmt in the @construct phases.
+    // The last group holds the previous token, or is `[]` after a clause
+    // keyword and `[expr]` after a path or field. Words that aren't
+    // keywords, like `until`, only start a clause after something that can
+    // end an expression, so they can still be used as names.
+
+    // The source of a `for` clause ends at the next clause or at the end.
+    (@preprocess[1] @in $opts:tt {for $($ts:tt)*} {$($procd_ts:tt)*} {$($src:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {for $($ts)*} {$($procd_ts)* $($src)*} $x) };
+    (@preprocess[1] @in $opts:tt {if $($ts:tt)*} {$($procd_ts:tt)*} {$($src:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {if $($ts)*} {$($procd_ts)* $($src)*} $x) };
+    (@preprocess[1] @in $opts:tt {let $($ts:tt)*} {$($procd_ts:tt)*} {$($src:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {let $($ts)*} {$($procd_ts)* $($src)*} $x) };
+    (@preprocess[1] @in $opts:tt {while $($ts:tt)*} {$($procd_ts:tt)*} {$($src:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {while $($ts)*} {$($procd_ts)* $($src)*} $x) };
+    (@preprocess[1] @in $opts:tt {break $($ts:tt)*} {$($procd_ts:tt)*} {$($src:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {break $($ts)*} {$($procd_ts)* $($src)*} $x) };
+    (@preprocess[1] @in $opts:tt {} {$($procd_ts:tt)*} {$($src:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {} {$($procd_ts)* $($src)*} $x) };
+    // A word right after a `..` might be the end of the range or a clause
+    (@preprocess[1] @in $opts:tt {$w:tt $t:tt $($ts:tt)*} {$($procd_ts:tt)*} {$($src:tt)*} [..]) => {
+        c!(@after_range $w $t {$($ts)*}
+            (@preprocess[1] $opts {$w $t $($ts)*} {$($procd_ts)* $($src)*} [expr])
+            (@preprocess[1] @in $opts {$t $($ts)*} {$($procd_ts)*} {$($src)* $w} [$w]))
+    };
+    (@preprocess[1] @in $opts:tt {. $t:tt $($ts:tt)*} $procd:tt {$($src:tt)*} $x:tt) =>
+        { c!(@preprocess[1] @in $opts {$($ts)*} $procd {$($src)* . $t} [expr]) };
+    (@preprocess[1] @in $opts:tt {:: $t:tt $($ts:tt)*} $procd:tt {$($src:tt)*} $x:tt) =>
+        { c!(@preprocess[1] @in $opts {$($ts)*} $procd {$($src)* :: $t} [expr]) };
+    // Anything after something that can end an expression might start a
+    // clause or a slice, so look at it in @expr. Skip `-` first, which the
+    // `literal` rule would try to parse as the start of a negative number.
+    (@preprocess[1] @in $opts:tt {$t:tt $($ts:tt)*} $procd:tt {$($src:tt)*} [-]) =>
+        { c!(@preprocess[1] @in $opts {$($ts)*} $procd {$($src)* $t} [$t]) };
+    (@preprocess[1] @in $opts:tt {$t:tt $($ts:tt)*} $procd:tt $src:tt [$f:ident]) =>
+        { c!(@preprocess[1] @in @expr $opts {$t $($ts)*} $procd $src) };
+    (@preprocess[1] @in $opts:tt {$t:tt $($ts:tt)*} $procd:tt $src:tt [$f:literal]) =>
+        { c!(@preprocess[1] @in @expr $opts {$t $($ts)*} $procd $src) };
+    (@preprocess[1] @in $opts:tt {$t:tt $($ts:tt)*} $procd:tt $src:tt [($($f:tt)*)]) =>
+        { c!(@preprocess[1] @in @expr $opts {$t $($ts)*} $procd $src) };
+    (@preprocess[1] @in $opts:tt {$t:tt $($ts:tt)*} $procd:tt $src:tt [[$($f:tt)*]]) =>
+        { c!(@preprocess[1] @in @expr $opts {$t $($ts)*} $procd $src) };
+    (@preprocess[1] @in $opts:tt {$t:tt $($ts:tt)*} $procd:tt $src:tt [{$($f:tt)*}]) =>
+        { c!(@preprocess[1] @in @expr $opts {$t $($ts)*} $procd $src) };
+    (@preprocess[1] @in $opts:tt {$t:tt $($ts:tt)*} $procd:tt $src:tt [?]) =>
+        { c!(@preprocess[1] @in @expr $opts {$t $($ts)*} $procd $src) };
+    (@preprocess[1] @in $opts:tt {zip @$mode:ident $fill:tt $($ts:tt)*} $procd:tt {$($src:tt)*} $x:tt) =>
+        { c!(@preprocess[1] @in $opts {$($ts)*} $procd {$($src)* zip} [zip]) };
+    (@preprocess[1] @in $opts:tt {$t:tt $($ts:tt)*} $procd:tt {$($src:tt)*} $x:tt) =>
+        { c!(@preprocess[1] @in $opts {$($ts)*} $procd {$($src)* $t} [$t]) };
+
+    // Brackets after an expression might hold a slice like `[1:-1:2]`, but
+    // not after a macro like `vec!`
+    (@preprocess[1] @in @expr $opts:tt {[$($sl:tt)*] $($ts:tt)*} $procd:tt $src:tt) =>
+        { c!(@slice[0] [$opts {$($ts)*} $procd $src [$($sl)*]] {$($sl)*} [] []) };
+    // A clause word after an expression ends the source
+    (@preprocess[1] @in @expr $opts:tt {until $($ts:tt)*} {$($procd_ts:tt)*} {$($src:tt)*}) =>
+        { c!(@preprocess[1] $opts {until $($ts)*} {$($procd_ts)* $($src)*} [expr]) };
+    (@preprocess[1] @in @expr $opts:tt {zip $($ts:tt)*} {$($procd_ts:tt)*} {$($src:tt)*}) =>
+        { c!(@preprocess[1] $opts {zip $($ts)*} {$($procd_ts)* $($src)*} [expr]) };
+    (@preprocess[1] @in @expr $opts:tt {step $($ts:tt)*} {$($procd_ts:tt)*} {$($src:tt)*}) =>
+        { c!(@preprocess[1] $opts {step $($ts)*} {$($procd_ts)* $($src)*} [expr]) };
+    (@preprocess[1] @in @expr $opts:tt {$t:tt $($ts:tt)*} $procd:tt {$($src:tt)*}) =>
+        { c!(@preprocess[1] @in $opts {$($ts)*} $procd {$($src)* $t} [$t]) };
 
     // ERROR: No loop body
-    (@preprocess[1] {$($ts:tt)*} {, $($procd_ts:tt)*}) =>
+    (@preprocess[1] $opts:tt {$($ts:tt)*} {, $($procd_ts:tt)*} $x:tt) =>
         { compile_error!("Missing loop body") };
-    // Replace `for` with `, for` and continue to next token
-    (@preprocess[1] {for $($ts:tt)*} {$($procd_ts:tt)*}) =>
-        { c!(@preprocess[1] {$($ts)*} {$($procd_ts)* , for}) };
+    // Replace `for await` with `, for @await`. A pat fragment can't skip
+    // over `await` without an error, but it can skip `@`.
+    (@preprocess[1] $opts:tt {for await @strict $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* , for @await @strict} []) };
+    (@preprocess[1] $opts:tt {for await $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* , for @await} []) };
+    // Replace `for` with `, for` and continue to next token, keeping the
+    // `@strict` mark out of the previous token
+    (@preprocess[1] $opts:tt {for @strict $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* , for @strict} []) };
+    (@preprocess[1] $opts:tt {for $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* , for} []) };
+    // An `if` right after `=` or `else` is part of an expression, like in
+    // `let y = if c { a } else { b }`, and so is a `let` right after it
+    (@preprocess[1] $opts:tt {if $($ts:tt)*} {$($procd_ts:tt)*} [=]) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* if} [if]) };
+    (@preprocess[1] $opts:tt {if $($ts:tt)*} {$($procd_ts:tt)*} [else]) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* if} [if]) };
+    (@preprocess[1] $opts:tt {let $($ts:tt)*} {$($procd_ts:tt)*} [if]) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* let} [let]) };
+    // Replace `if let` with `, if let` and continue to next token
+    (@preprocess[1] $opts:tt {if let $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* , if let} []) };
     // Replace `if` with `, if` and continue to next token
-    (@preprocess[1] {if $($ts:tt)*} {$($procd_ts:tt)*}) =>
-        { c!(@preprocess[1] {$($ts)*} {$($procd_ts)* , if}) };
+    (@preprocess[1] $opts:tt {if $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* , if} []) };
+    // Replace `while` with `, while` and continue to next token
+    (@preprocess[1] $opts:tt {while $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* , while} []) };
+    // Replace `break if` with `, until` and continue to next token
+    (@preprocess[1] $opts:tt {break if $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* , until} []) };
+    // Leave fields, methods and functions named `zip`, `step` or `linspace`
+    // alone
+    (@preprocess[1] $opts:tt {. $t:tt $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* . $t} [expr]) };
+    (@preprocess[1] $opts:tt {:: $t:tt $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* :: $t} [expr]) };
+    // Replace `in linspace(...)` with a call to the helper
+    (@preprocess[1] $opts:tt {in linspace($($args:tt)*) $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* in $crate::__private::linspace($($args)*)} [expr]) };
+    // Collect the source after `in` separately, so that a slice can wrap it
+    (@preprocess[1] $opts:tt {in $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] @in $opts {$($ts)*} {$($procd_ts)* in} {} []) };
+    // Replace `let` with `, let` and continue to next token
+    (@preprocess[1] $opts:tt {let $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* , let} []) };
 
+    // Keywords before a name (`let mut until`) don't end an expression
+    (@preprocess[1] $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*} [as]) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* $t} [$t]) };
+    (@preprocess[1] $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*} [else]) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* $t} [$t]) };
+    (@preprocess[1] $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*} [match]) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* $t} [$t]) };
+    (@preprocess[1] $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*} [move]) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* $t} [$t]) };
+    (@preprocess[1] $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*} [mut]) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* $t} [$t]) };
+    (@preprocess[1] $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*} [ref]) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* $t} [$t]) };
+    (@preprocess[1] $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*} [return]) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* $t} [$t]) };
+    // A word after something that can end an expression might start a
+    // clause, so look at it in @expr. Skip `-` first, as above.
+    (@preprocess[1] $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*} [-]) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* $t} [$t]) };
+    (@preprocess[1] $opts:tt {$w:ident $($ts:tt)*} $procd:tt [$f:ident]) =>
+        { c!(@preprocess[1] @expr $opts {$w $($ts)*} $procd) };
+    (@preprocess[1] $opts:tt {$w:ident $($ts:tt)*} $procd:tt [$f:literal]) =>
+        { c!(@preprocess[1] @expr $opts {$w $($ts)*} $procd) };
+    (@preprocess[1] $opts:tt {$w:ident $($ts:tt)*} $procd:tt [($($f:tt)*)]) =>
+        { c!(@preprocess[1] @expr $opts {$w $($ts)*} $procd) };
+    (@preprocess[1] $opts:tt {$w:ident $($ts:tt)*} $procd:tt [[$($f:tt)*]]) =>
+        { c!(@preprocess[1] @expr $opts {$w $($ts)*} $procd) };
+    (@preprocess[1] $opts:tt {$w:ident $($ts:tt)*} $procd:tt [{$($f:tt)*}]) =>
+        { c!(@preprocess[1] @expr $opts {$w $($ts)*} $procd) };
+    (@preprocess[1] $opts:tt {$w:ident $($ts:tt)*} $procd:tt [?]) =>
+        { c!(@preprocess[1] @expr $opts {$w $($ts)*} $procd) };
+    // Otherwise it's a name, like the function in `zip(xs, ys)`
+    (@preprocess[1] $opts:tt {zip @$mode:ident $fill:tt $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* zip} [zip]) };
     // Continue to next token
-    (@preprocess[1] {$t:tt $($ts:tt)*} {$($procd_ts:tt)*}) =>
-        { c!(@preprocess[1] {$($ts)*} {$($procd_ts)* $t}) };
+    (@preprocess[1] $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* $t} [$t]) };
+
+    // Continue to @rewrite[0] or @preprocess[2]
+    (@preprocess[1] $opts:tt {} {@rewrite $($procd_ts:tt)*} $x:tt) =>
+        { c!(@rewrite[0] $opts {$($procd_ts)*} {}) };
+    (@preprocess[1] $opts:tt {} {$($procd_ts:tt)*} $x:tt) =>
+        { c!(@preprocess[2] $opts [] $($procd_ts)*) };
+
+    // Replace `until` after an expression with `, until`, and `zip` or
+    // `step` with `, zip @mode [fill]` or `, step`. Mark the comprehension
+    // for @rewrite[0] by starting it with `@rewrite`.
+    (@preprocess[1] @expr $opts:tt {until $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* , until} []) };
+    (@preprocess[1] @expr $opts:tt {zip $($ts:tt)*} $procd:tt) =>
+        { c!(@preprocess[1] @zip $opts {$($ts)*} $procd) };
+    (@preprocess[1] @expr $opts:tt {step $($ts:tt)*} $procd:tt) =>
+        { c!(@preprocess[1] @step $opts {$($ts)*} $procd) };
+    (@preprocess[1] @expr $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {$($procd_ts)* $t} [$t]) };
+
+    (@preprocess[1] @step $opts:tt {$($ts:tt)*} {@rewrite $($procd_ts:tt)*}) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {@rewrite $($procd_ts)* , step} []) };
+    (@preprocess[1] @step $opts:tt {$($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {@rewrite $($procd_ts)* , step} []) };
+    (@preprocess[1] @zip $opts:tt {@$mode:ident $fill:tt $($ts:tt)*} {@rewrite $($procd_ts:tt)*}) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {@rewrite $($procd_ts)* , zip @$mode $fill} []) };
+    (@preprocess[1] @zip $opts:tt {@$mode:ident $fill:tt $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[1] $opts {$($ts)*} {@rewrite $($procd_ts)* , zip @$mode $fill} []) };
+    (@preprocess[1] @zip $opts:tt {$($ts:tt)*} $procd:tt) =>
+        { c!(@preprocess[1] @zip $opts {@shortest [] $($ts)*} $procd) };
+
+    // Continue with the first group if the word after a `..` starts a clause
+    // (`1.. until x > 9`), or with the second one if it ends the range
+    // (`0..until`). The third group holds the tokens after the next one.
+    (@after_range until ($($g:tt)*) $ts:tt $yes:tt $no:tt) => { c! $no };
+    (@after_range until $t:literal $ts:tt $yes:tt $no:tt) => { c! $yes };
+    (@after_range $w:tt as $ts:tt $yes:tt $no:tt) => { c! $no };
+    (@after_range $w:tt break $ts:tt $yes:tt $no:tt) => { c! $no };
+    (@after_range $w:tt for $ts:tt $yes:tt $no:tt) => { c! $no };
+    (@after_range $w:tt if $ts:tt $yes:tt $no:tt) => { c! $no };
+    (@after_range $w:tt in $ts:tt $yes:tt $no:tt) => { c! $no };
+    (@after_range $w:tt let $ts:tt $yes:tt $no:tt) => { c! $no };
+    (@after_range $w:tt step $ts:tt $yes:tt $no:tt) => { c! $no };
+    (@after_range $w:tt until $ts:tt $yes:tt $no:tt) => { c! $no };
+    (@after_range $w:tt while $ts:tt $yes:tt $no:tt) => { c! $no };
+    (@after_range $w:tt zip $ts:tt $yes:tt $no:tt) => { c! $no };
+    (@after_range until $t:ident $ts:tt $yes:tt $no:tt) => { c! $yes };
+    (@after_range zip $t:ident $ts:tt $yes:tt $no:tt) => { c! $yes };
+    (@after_range zip _ $ts:tt $yes:tt $no:tt) => { c! $yes };
+    (@after_range zip @ $ts:tt $yes:tt $no:tt) => { c! $yes };
+    (@after_range zip ($($g:tt)*) {in $($ts:tt)*} $yes:tt $no:tt) => { c! $yes };
+    (@after_range zip [$($g:tt)*] {in $($ts:tt)*} $yes:tt $no:tt) => { c! $yes };
+    (@after_range $w:tt $t:tt $ts:tt $yes:tt $no:tt) => { c! $no };
+
+    // Wrap each range followed by a `step` clause in a stepped iterator, and
+    // replace each `for` clause followed by `zip` clauses with a single `for`
+    // over the zipped iterators, binding nested tuples of their patterns.
+    (@rewrite[0] $opts:tt {, for @await $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@rewrite[0] $opts {$($ts)*} {$($procd_ts)* , for @await}) };
+    (@rewrite[0] $opts:tt {, for $(@$strict:ident)? $p:pat in $range:expr, step $step:expr $(, $($ts:tt)*)?} $procd:tt) =>
+        { c!(@rewrite[0] $opts {, for $(@$strict)? $p in c!(@step $range, $step) $(, $($ts)*)?} $procd) };
+    (@rewrite[0] $opts:tt {, for $(@$strict:ident)? $p:pat in $iter:expr, zip @$mode:ident $fill:tt $($ts:tt)*} $procd:tt) =>
+        { c!(@rewrite[1] $opts [$(@$strict)?] [$mode $fill] $p [$iter] {$($ts)*} $procd) };
+    // ERROR: `zip` or `step` without a `for`
+    (@rewrite[0] $opts:tt {, zip $($ts:tt)*} $procd:tt) =>
+        { compile_error!("`zip` must follow a `for ... in ...` clause") };
+    (@rewrite[0] $opts:tt {, step $($ts:tt)*} $procd:tt) =>
+        { compile_error!("`step` must follow a `for ... in ...` clause") };
+    (@rewrite[0] $opts:tt {$t:tt $($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@rewrite[0] $opts {$($ts)*} {$($procd_ts)* $t}) };
+    (@rewrite[0] $opts:tt {} {$($procd_ts:tt)*}) =>
+        { c!(@preprocess[2] $opts [] $($procd_ts)*) };
+
+    // Add the source after a `zip` to the group.
+    (@rewrite[1] $opts:tt $strict:tt $mode:tt $pat:tt $iters:tt {$p:pat in $range:expr, step $step:expr $(, $($ts:tt)*)?} $procd:tt) =>
+        { c!(@rewrite[1] $opts $strict $mode $pat $iters {$p in c!(@step $range, $step) $(, $($ts)*)?} $procd) };
+    (@rewrite[1] $opts:tt $strict:tt $mode:tt $pat:tt [$($iter:expr),+] {$p:pat in $iter2:expr $(, $($ts:tt)*)?} $procd:tt) =>
+        { c!(@rewrite[2] $opts $strict $mode ($pat, $p) [$($iter,)+ $iter2] {$(, $($ts)*)?} $procd) };
+    (@rewrite[1] $opts:tt $strict:tt $mode:tt $pat:tt $iters:tt $ts:tt $procd:tt) =>
+        { compile_error!("Invalid zip clause") };
+    // Continue the group if another `zip` follows, otherwise replace it.
+    (@rewrite[2] $opts:tt $strict:tt $mode:tt $pat:tt $iters:tt {, zip @$m:ident $fill:tt $($ts:tt)*} $procd:tt) =>
+        { c!(@rewrite[1] $opts $strict $mode $pat $iters {$($ts)*} $procd) };
+    (@rewrite[2] $opts:tt [$($strict:tt)*] $mode:tt $pat:tt $iters:tt {$($ts:tt)*} {$($procd_ts:tt)*}) =>
+        { c!(@rewrite[0] $opts {$($ts)*} {$($procd_ts)* , for $($strict)* $pat in c!(@zip[1] $mode $iters)}) };
+
+    // Step over a range. A range like `10..0` is empty on its own, so Clippy
+    // would reject it.
+    (@step $range:expr, $step:expr) => {{
+        #[allow(clippy::reversed_empty_ranges)]
+        let range = $range;
+        $crate::__private::step(range, $step)
+    }};
+
+    // Split the brackets after a source at each `:`. Without a `:` they're
+    // part of the source, like `rows[0]`, and so are brackets that can't be a
+    // slice, like `[|a: i32| a + 1]`. A `::` is a path unless it starts the
+    // slice or a literal, `-` or `(...)` follows it, like in `[::-1]`.
+    (@slice[0] [$opts:tt $ts:tt $procd:tt {$($src:tt)*} $orig:tt] {, $($sl:tt)*} $parts:tt $cur:tt) =>
+        { c!(@preprocess[1] @in $opts $ts $procd {$($src)* $orig} [expr]) };
+    (@slice[0] [$opts:tt $ts:tt $procd:tt {$($src:tt)*} $orig:tt] {; $($sl:tt)*} $parts:tt $cur:tt) =>
+        { c!(@preprocess[1] @in $opts $ts $procd {$($src)* $orig} [expr]) };
+    (@slice[0] [$opts:tt $ts:tt $procd:tt {$($src:tt)*} $orig:tt] {| $($sl:tt)*} $parts:tt $cur:tt) =>
+        { c!(@preprocess[1] @in $opts $ts $procd {$($src)* $orig} [expr]) };
+    (@slice[0] [$opts:tt $ts:tt $procd:tt {$($src:tt)*} $orig:tt] {|| $($sl:tt)*} $parts:tt $cur:tt) =>
+        { c!(@preprocess[1] @in $opts $ts $procd {$($src)* $orig} [expr]) };
+    (@slice[0] [$opts:tt $ts:tt $procd:tt {$($src:tt)*} $orig:tt] {= $($sl:tt)*} $parts:tt $cur:tt) =>
+        { c!(@preprocess[1] @in $opts $ts $procd {$($src)* $orig} [expr]) };
+    (@slice[0] [$opts:tt $ts:tt $procd:tt {$($src:tt)*} $orig:tt] {=> $($sl:tt)*} $parts:tt $cur:tt) =>
+        { c!(@preprocess[1] @in $opts $ts $procd {$($src)* $orig} [expr]) };
+    (@slice[0] $k:tt {: $($sl:tt)*} [$($parts:tt)*] [$($cur:tt)*]) =>
+        { c!(@slice[0] $k {$($sl)*} [$($parts)* [$($cur)*]] []) };
+    (@slice[0] $k:tt {:: $l:literal $($sl:tt)*} [$($parts:tt)*] [$($cur:tt)*]) =>
+        { c!(@slice[0] $k {$l $($sl)*} [$($parts)* [$($cur)*] []] []) };
+    (@slice[0] $k:tt {:: - $($sl:tt)*} [$($parts:tt)*] [$($cur:tt)*]) =>
+        { c!(@slice[0] $k {- $($sl)*} [$($parts)* [$($cur)*] []] []) };
+    (@slice[0] $k:tt {:: ($($g:tt)*) $($sl:tt)*} [$($parts:tt)*] [$($cur:tt)*]) =>
+        { c!(@slice[0] $k {($($g)*) $($sl)*} [$($parts)* [$($cur)*] []] []) };
+    (@slice[0] $k:tt {::} [$($parts:tt)*] [$($cur:tt)*]) =>
+        { c!(@slice[0] $k {} [$($parts)* [$($cur)*] []] []) };
+    (@slice[0] $k:tt {:: $($sl:tt)*} [$($parts:tt)*] []) =>
+        { c!(@slice[0] $k {$($sl)*} [$($parts)* [] []] []) };
+    (@slice[0] $k:tt {$t:tt $($sl:tt)*} $parts:tt [$($cur:tt)*]) =>
+        { c!(@slice[0] $k {$($sl)*} $parts [$($cur)* $t]) };
+    (@slice[0] [$opts:tt $ts:tt $procd:tt {$($src:tt)*} $orig:tt] {} [] $cur:tt) =>
+        { c!(@preprocess[1] @in $opts $ts $procd {$($src)* $orig} [expr]) };
+    (@slice[0] $k:tt {} [$a:tt] $b:tt) => { c!(@slice[1] $k [$a $b []]) };
+    (@slice[0] $k:tt {} [$a:tt $b:tt] $c:tt) => { c!(@slice[1] $k [$a $b $c]) };
+    (@slice[0] $k:tt {} $parts:tt $cur:tt) =>
+        { compile_error!("Invalid slice, expected `[start:end]` or `[start:end:step]`") };
+    // A negative literal needs the length of the source, so check that it's
+    // known at compile time
+    (@slice[1] $k:tt [[- $($a:tt)*] $b:tt $c:tt]) => { c!(@slice[2] $k sliced_sized [[- $($a)*] $b $c]) };
+    (@slice[1] $k:tt [$a:tt [- $($b:tt)*] $c:tt]) => { c!(@slice[2] $k sliced_sized [$a [- $($b)*] $c]) };
+    (@slice[1] $k:tt [$a:tt $b:tt [- $($c:tt)*]]) => { c!(@slice[2] $k sliced_sized [$a $b [- $($c)*]]) };
+    (@slice[1] $k:tt $parts:tt) => { c!(@slice[2] $k sliced $parts) };
+    // `slice` walks the source backwards without buffering it if it can
+    (@slice[2] [$opts:tt $ts:tt $procd:tt {$($src:tt)*} $orig:tt] $f:ident [[$($a:tt)*] [$($b:tt)*] [$($c:tt)*]]) => {
+        c!(@preprocess[1] @in $opts $ts $procd {{
+            #[allow(unused_imports)]
+            use $crate::__private::{SliceBack as _, SliceBuffered as _};
+            $crate::__private::$f($($src)*).slice(c!(@index $($a)*), c!(@index $($b)*), c!(@index $($c)*))
+        }} [expr])
+    };
+    (@index) => { None };
+    (@index $($i:tt)+) => { Some(($($i)+) as isize) };
+
+    // Zip the iterators of a group, nesting a tuple for each one after the
+    // second. `longest` takes one fill value per iterator.
+    (@zip[1] [shortest []] [$a:expr]) => { $a };
+    (@zip[1] [shortest []] [$a:expr, $b:expr $(, $iter:expr)*]) =>
+        { c!(@zip[1] [shortest []] [Iterator::zip(IntoIterator::into_iter($a), $b) $(, $iter)*]) };
+    (@zip[1] [strict []] [$a:expr]) => { $a };
+    (@zip[1] [strict []] [$a:expr, $b:expr $(, $iter:expr)*]) =>
+        { c!(@zip[1] [strict []] [$crate::__private::zip_eq($a, $b) $(, $iter)*]) };
+    (@zip[1] [longest [$($fill:expr),+]] [$($iter:expr),+]) =>
+        { c!(@zip[2] $(($iter, $fill)),+) };
+    (@zip[2] ($a:expr, $fill_a:expr), ($b:expr, $fill_b:expr) $(, $rest:tt)*) =>
+        { c!(@zip[3] $crate::__private::zip_longest($a, $b, $fill_a, $fill_b) $(, $rest)*) };
+    (@zip[3] $z:expr) => { $z };
+    (@zip[3] $z:expr, ($b:expr, $fill_b:expr) $(, $rest:tt)*) => {
+        c!(@zip[3] {
+            let z = $z;
+            let fill = z.fill();
+            $crate::__private::zip_longest(z, $b, fill, $fill_b)
+        } $(, $rest)*)
+    };
+
+
+    // Classify the loop body. Map bodies other than `k: v` are rewritten as
+    // `@entry [k] {...}`, which can't be mistaken for an expression.
+    // `group v` and `merge v with f` are matched after they fail to parse as
+    // one, so that `group` and `merge` still work as names. The second group
+    // collects the outer keys of a nested map body (`a: b: v`),
+    // innermost first.
+
+    (@preprocess[2] $opts:tt [$($outer:tt)*] $k:expr => $k2:expr => $($ts:tt)*) =>
+        { c!(@preprocess[2] $opts [[$k] $($outer)*] $k2 => $($ts)*) };
+    (@preprocess[2] $opts:tt [] $k:expr => $v:expr, for $($rest:tt)*) =>
+        { c!(@construct[0] $opts $k => $v, for $($rest)*) };
+    (@preprocess[2] $opts:tt $outer:tt $k:expr => $v:expr, for $($rest:tt)*) =>
+        { c!(@nest $opts $outer [$k] {set [$v]}, for $($rest)*) };
+    (@preprocess[2] $opts:tt $outer:tt $k:expr => merge $v:expr, for $($rest:tt)*) =>
+        { compile_error!("Expected `merge v with f`") };
+    (@preprocess[2] $opts:tt $outer:tt $k:expr => group $($ts:tt)*) =>
+        { c!(@group $opts $outer [$k] [] $($ts)*) };
+    (@preprocess[2] $opts:tt $outer:tt $k:expr => $op:tt $v:expr, for $($rest:tt)*) =>
+        { c!(@nest $opts $outer [$k] {update [$op] [$v]}, for $($rest)*) };
+    (@preprocess[2] $opts:tt $outer:tt $k:expr => merge $($ts:tt)*) =>
+        { c!(@merge $opts $outer [$k] [] $($ts)*) };
 
     // Done with preprocessing, continue to @construct[0]
-    (@preprocess[1] {} {$($procd_ts:tt)*}) =>
-        { c!(@construct[0] $($procd_ts)*) };
+    (@preprocess[2] $opts:tt [] $($procd_ts:tt)*) =>
+        { c!(@construct[0] $opts $($procd_ts)*) };
+    // ERROR: Nested map body without a value
+    (@preprocess[2] $opts:tt $outer:tt $($procd_ts:tt)*) =>
+        { compile_error!("Invalid nested map comprehension body") };
+
+    // Split `group v as T` at the last `as` followed by a type.
+    (@group $opts:tt $outer:tt $k:tt [$($v:tt)*] as $t:ty, for $($rest:tt)*) =>
+        { c!(@nest $opts $outer $k {group [$($v)*] [$t]}, for $($rest)*) };
+    (@group $opts:tt $outer:tt $k:tt [$($v:tt)*] , for $($rest:tt)*) =>
+        { c!(@nest $opts $outer $k {group [$($v)*] []}, for $($rest)*) };
+    (@group $opts:tt $outer:tt $k:tt [$($v:tt)*] $t:tt $($rest:tt)*) =>
+        { c!(@group $opts $outer $k [$($v)* $t] $($rest)*) };
+
+    // Split `merge v with f` at `with`.
+    (@merge $opts:tt $outer:tt $k:tt $v:tt with $f:expr, for $($rest:tt)*) =>
+        { c!(@nest $opts $outer $k {merge $v [$f]}, for $($rest)*) };
+    (@merge $opts:tt $outer:tt $k:tt $v:tt , for $($rest:tt)*) =>
+        { compile_error!("Expected `merge v with f`") };
+    (@merge $opts:tt $outer:tt $k:tt [$($v:tt)*] $t:tt $($rest:tt)*) =>
+        { c!(@merge $opts $outer $k [$($v)* $t] $($rest)*) };
+
+    // Wrap an entry in the outer keys of a nested map body.
+    (@nest $opts:tt [$ko:tt $($outer:tt)*] $k:tt $how:tt $($rest:tt)*) =>
+        { c!(@nest $opts [$($outer)*] $ko {nest $k $how} $($rest)*) };
+    (@nest $opts:tt [] $k:tt $how:tt $($rest:tt)*) =>
+        { c!(@construct[0] $opts @entry $k $how $($rest)*) };
 
 
     // Start constructing the result.
+    // If the loop body is a future, build a stream of the bodies that runs
+    // them concurrently, then collect their outputs by draining it.
+    (@construct[0] {{iter $($i:tt)*} $hasher:tt $cap:tt $dup:tt [$($conc:tt)+]} $($rest:tt)*) => {{
+        compile_error!("`iter` comprehensions can't be buffered or parallel")
+    }};
+    (@construct[0] {{in $($c:tt)*} $hasher:tt $cap:tt $dup:tt [$($conc:tt)+]} $($rest:tt)*) => {{
+        compile_error!("In-place comprehensions can't be buffered or parallel")
+    }};
+    (@construct[0] {$coll:tt $hasher:tt $cap:tt $dup:tt [$mode:ident $n:expr]} $k:expr => $v:expr, for $($rest:tt)*) => {{
+        let futures = c!(@iter [] { let k = $k; let v = $v; async move { (k, v.await) } }, for $($rest)*);
+        let stream = $crate::__comprende_futures!(stream::iter)(futures);
+        let stream = $crate::__comprende_futures!(StreamExt::$mode)(stream, $n);
+        c!(@construct[0] {$coll $hasher $cap $dup []} k => v, for @await (k, v) in stream)
+    }};
+    (@construct[0] {$coll:tt $hasher:tt $cap:tt $dup:tt [$mode:ident $n:expr]} $e:expr, for $($rest:tt)*) => {{
+        let futures = c!(@iter [] $e, for $($rest)*);
+        let stream = $crate::__comprende_futures!(stream::iter)(futures);
+        let stream = $crate::__comprende_futures!(StreamExt::$mode)(stream, $n);
+        c!(@construct[0] {$coll $hasher $cap $dup []} x, for @await x in stream)
+    }};
+    (@construct[0] {$coll:tt $hasher:tt $cap:tt $dup:tt [$mode:ident $n:expr]} $($rest:tt)*) => {{
+        compile_error!(concat!("`", stringify!($mode), "` requires an expression or `k: v` loop body"))
+    }};
+    // Run the outermost loop in parallel, and the rest of the loops in each
+    // of its iterations.
+    (@construct[0] {{} [] [] [] [par]} $k:expr => $v:expr, for $($rest:tt)*) => {{
+        c!(@par [std::collections::HashMap<_, _>] ($k, $v), for $($rest)*)
+    }};
+    (@construct[0] {{} [] [] [] [par]} $e:expr, for $($rest:tt)*) => {{
+        c!(@par [Vec<_>] $e, for $($rest)*)
+    }};
+    (@construct[0] {{set} [] [] [] [par]} $e:expr, for $($rest:tt)*) => {{
+        c!(@par [std::collections::HashSet<_>] $e, for $($rest)*)
+    }};
+    (@construct[0] {{type $t:ty} [] [] [] [par]} $k:expr => $v:expr, for $($rest:tt)*) => {{
+        c!(@par [$t] ($k, $v), for $($rest)*)
+    }};
+    (@construct[0] {{type $t:ty} [] [] [] [par]} $e:expr, for $($rest:tt)*) => {{
+        c!(@par [$t] $e, for $($rest)*)
+    }};
+    (@construct[0] {{} [] [] [] [par]} $s:stmt;, for @await $($rest:tt)*) => {{
+        compile_error!("`for await` can't be used in a lazy, buffered or parallel comprehension")
+    }};
+    (@construct[0] {{} [] [] [] [par]} $s:stmt;, for $(@$strict:ident)? $p:pat in $iter:expr $(, $($rest:tt)*)?) => {{
+        let iter = $crate::__comprende_rayon!(iter::IntoParallelIterator::into_par_iter)($iter);
+        $crate::__comprende_rayon!(iter::ParallelIterator::for_each)(iter, |item| match item {
+            $p => {
+                c![@construct[2] [] [] $s $(, $($rest)*)?];
+            }
+            #[allow(unreachable_patterns)]
+            _ => c!(@mismatch [$($strict)?] $p, ()),
+        })
+    }};
+    (@construct[0] {$coll:tt $hasher:tt $cap:tt $dup:tt [par]} $($rest:tt)*) => {{
+        compile_error!("`par` requires a vector, map, set or statement comprehension, or a type implementing `FromParallelIterator`")
+    }};
+
+    // A single `for` with no conditions produces one element per item of its
+    // iterator, so reserve space for the iterator's lower bound up front. The
+    // `match` keeps temporaries in the source alive for the whole loop.
+    (@construct[0] {{$($kind:ident)*} $hasher:tt [] $dup:tt []} $k:expr => $v:expr, for $p:pat in $iter:expr) => {{
+        match IntoIterator::into_iter($iter) {
+            iter => c!(@construct[0] {{$($kind)*} $hasher [iter.size_hint().0] $dup []} $k => $v, for $p in iter),
+        }
+    }};
+    (@construct[0] {{$($kind:ident)*} $hasher:tt [] $dup:tt []} $e:expr, for $p:pat in $iter:expr) => {{
+        match IntoIterator::into_iter($iter) {
+            iter => c!(@construct[0] {{$($kind)*} $hasher [iter.size_hint().0] $dup []} $e, for $p in iter),
+        }
+    }};
+
     // If the loop body is an expression, create the appropriate collection.
-    (@construct[0] $k:expr => $v:expr, for $($rest:tt)*) => {{
-        let mut m = std::collections::HashMap::new();
-        c![@construct[1] {m.insert($k, $v);}, for $($rest)*];
+    (@construct[0] {{$(ordered)? set} $hasher:tt $cap:tt $dup:tt []} $k:expr => $v:expr, for $($rest:tt)*) => {{
+        compile_error!("Set comprehension body can't contain a `:`")
+    }};
+    (@construct[0] {{$(ordered)? set} $hasher:tt $cap:tt $dup:tt []} @entry $($rest:tt)*) => {{
+        compile_error!("Set comprehension body can't contain a `:`")
+    }};
+    (@construct[0] {{$($kind:ident)*} $hasher:tt $cap:tt [error] []} $k:expr => $v:expr, for $($rest:tt)*) => {{
+        'c: {
+            let mut m = c!(@new {$($kind)* map} $hasher);
+            c!(@reserve m $cap);
+            c![@construct[1] {c!(@insert m [error 'c] $k, $v);}, for $($rest)*];
+            Ok(m)
+        }
+    }};
+    (@construct[0] {{$($kind:ident)*} $hasher:tt $cap:tt $dup:tt []} $k:expr => $v:expr, for $($rest:tt)*) => {{
+        let mut m = c!(@new {$($kind)* map} $hasher);
+        c!(@reserve m $cap);
+        c![@construct[1] {c!(@insert m $dup $k, $v);}, for $($rest)*];
+        m
+    }};
+    (@construct[0] {{$($kind:ident)*} $hasher:tt $cap:tt [error] []} @entry $k:tt $how:tt, for $($rest:tt)*) => {{
+        'c: {
+            let mut m = c!(@new {$($kind)* map} $hasher);
+            c!(@reserve m $cap);
+            c![@construct[1] {c!(@insert m [error 'c] @entry {$($kind)*} $k $how);}, for $($rest)*];
+            Ok(m)
+        }
+    }};
+    (@construct[0] {{$($kind:ident)*} $hasher:tt $cap:tt $dup:tt []} @entry $k:tt $how:tt, for $($rest:tt)*) => {{
+        let mut m = c!(@new {$($kind)* map} $hasher);
+        c!(@reserve m $cap);
+        c![@construct[1] {c!(@insert m $dup @entry {$($kind)*} $k $how);}, for $($rest)*];
         m
     }};
-    (@construct[0] $e:expr, for $($rest:tt)*) => {{
+    (@construct[0] {$coll:tt $hasher:tt $cap:tt [$($dup:tt)+] []} $($rest:tt)*) => {{
+        compile_error!("`on_duplicate` requires a map comprehension without a collection type")
+    }};
+    (@construct[0] {{ordered} $hasher:tt $cap:tt $dup:tt []} $e:expr, for $($rest:tt)*) => {{
+        compile_error!("`ordered` requires a map or set comprehension")
+    }};
+    (@construct[0] {{$($kind:ident)+} $hasher:tt $cap:tt $dup:tt []} $e:expr, for $($rest:tt)*) => {{
+        let mut s = c!(@new {$($kind)+} $hasher);
+        c!(@reserve s $cap);
+        c![@construct[1] {s.insert($e);}, for $($rest)*];
+        s
+    }};
+    (@construct[0] {{$($coll:tt)*} [$($h:tt)+] $cap:tt $dup:tt []} $($rest:tt)*) => {{
+        compile_error!("`with_hasher` requires a map or set comprehension without a collection type")
+    }};
+    (@construct[0] {{} [] $cap:tt [] []} $e:expr, for $($rest:tt)*) => {{
         let mut v = Vec::new();
+        c!(@reserve v $cap);
         c![@construct[1] v.push($e), for $($rest)*];
         v
     }};
-    (@construct[0] $s:stmt;, for $($rest:tt)*) => {{
+    (@construct[0] {{$(array $($a:tt)*)? $(display $($d:tt)*)? $(iter $($i:tt)*)? $(stream $($st:tt)*)?} [] [$($n:tt)+] [] []} $($rest:tt)*) => {{
+        compile_error!("`with_capacity` requires a growable collection")
+    }};
+    (@construct[0] {{} [] [] [] []} $s:stmt;, for $($rest:tt)*) => {{
         c![@construct[1] $s, for $($rest)*];
     }};
+    (@construct[0] {{into [$out:expr]} [] $cap:tt [] []} $k:expr => $v:expr, for $($rest:tt)*) => {{
+        use $crate::__private::Target as _;
+        let out = $out.target();
+        c!(@reserve out $cap);
+        c![@construct[1] {out.extend(std::iter::once(($k, $v)));}, for $($rest)*];
+    }};
+    (@construct[0] {{into [$out:expr]} [] $cap:tt [] []} @entry $k:tt $how:tt, for $($rest:tt)*) => {{
+        use $crate::__private::Target as _;
+        let out = $out.target();
+        c!(@reserve out $cap);
+        c![@construct[1] {c!(@insert out [] @entry {_} $k $how);}, for $($rest)*];
+    }};
+    (@construct[0] {{into [$out:expr]} [] $cap:tt [] []} $e:expr, for $($rest:tt)*) => {{
+        use $crate::__private::Target as _;
+        let out = $out.target();
+        c!(@reserve out $cap);
+        c![@construct[1] {out.extend(std::iter::once($e));}, for $($rest)*];
+    }};
+    (@construct[0] {{iter [$($mv:tt)?]} [] [] [] []} $k:expr => $v:expr, for $($rest:tt)*) => {{
+        c!(@iter [$($mv)?] ($k, $v), for $($rest)*)
+    }};
+    (@construct[0] {{iter [$($mv:tt)?]} [] [] [] []} $e:expr, for $($rest:tt)*) => {{
+        c!(@iter [$($mv)?] $e, for $($rest)*)
+    }};
+    (@construct[0] {{stream [move]} [] [] [] []} $k:expr => $v:expr, for $($rest:tt)*) => {{
+        c!(@construct[0] {{stream [move]} [] [] [] []} ($k, $v), for $($rest)*)
+    }};
+    (@construct[0] {{stream [move]} [] [] [] []} $e:expr, for $($rest:tt)*) => {{
+        $crate::__comprende_futures!(@private generator)(|tx| async move {
+            c![@construct[1] {tx.send($e).await;}, for $($rest)*];
+        })
+    }};
+    (@construct[0] {{in [$c:expr]} [] [] [] []} $e:expr, for $(@$strict:ident)? $p:pat) => {{
+        use $crate::__private::Target as _;
+        $crate::__private::Update::update($c.target(), |item| {
+            let $p = item;
+            Some($e)
+        })
+    }};
+    (@construct[0] {{in [$c:expr]} [] [] [] []} $e:expr, for $(@$strict:ident)? $p:pat, $($rest:tt)*) => {{
+        use $crate::__private::Target as _;
+        $crate::__private::Update::update($c.target(), |item| {
+            let $p = item;
+            c![@construct[2] [] [] {return Some($e);}, $($rest)*];
+            None
+        })
+    }};
+    (@construct[0] {{in [$c:expr]} [] [] [] []} $e:expr, for $(@$strict:ident)? $p:pat in $($rest:tt)*) => {{
+        compile_error!("In-place comprehensions iterate over the collection itself, remove `in ...`")
+    }};
+    (@construct[0] {{type $t:ty} [] $cap:tt [] []} $k:expr => $v:expr, for $($rest:tt)*) => {{
+        let mut c: $t = Default::default();
+        c!(@reserve c $cap);
+        c![@construct[1] {Extend::extend(&mut c, std::iter::once(($k, $v)));}, for $($rest)*];
+        c
+    }};
+    (@construct[0] {{type $t:ty} [] $cap:tt [] []} @entry $k:tt $how:tt, for $($rest:tt)*) => {{
+        let mut c: $t = Default::default();
+        c!(@reserve c $cap);
+        c![@construct[1] {c!(@insert c [] @entry {_} $k $how);}, for $($rest)*];
+        c
+    }};
+    (@construct[0] {{type $t:ty} [] $cap:tt [] []} $e:expr, for $($rest:tt)*) => {{
+        let mut c: $t = Default::default();
+        c!(@reserve c $cap);
+        c![@construct[1] {Extend::extend(&mut c, std::iter::once($e));}, for $($rest)*];
+        c
+    }};
+    (@construct[0] {{slice [$($ptr:tt)*] $t:ty} [] $cap:tt [] []} $k:expr => $v:expr, for $($rest:tt)*) => {{
+        let mut v: Vec<$t> = Vec::new();
+        c!(@reserve v $cap);
+        c![@construct[1] v.push(($k, $v)), for $($rest)*];
+        <$($ptr)*<[$t]>>::from(v)
+    }};
+    (@construct[0] {{slice [$($ptr:tt)*] $t:ty} [] $cap:tt [] []} $e:expr, for $($rest:tt)*) => {{
+        let mut v: Vec<$t> = Vec::new();
+        c!(@reserve v $cap);
+        c![@construct[1] v.push($e), for $($rest)*];
+        <$($ptr)*<[$t]>>::from(v)
+    }};
+    (@construct[0] {{array $t:ty [$n:expr]} [] $cap:tt [] []} $k:expr => $v:expr, for $($rest:tt)*) => {{
+        let mut a = $crate::__private::ArrayBuilder::<$t, { $n }>::new();
+        c![@construct[1] a.push(($k, $v)), for $($rest)*];
+        a.finish()
+    }};
+    (@construct[0] {{array $t:ty [$n:expr]} [] $cap:tt [] []} $e:expr, for $($rest:tt)*) => {{
+        let mut a = $crate::__private::ArrayBuilder::<$t, { $n }>::new();
+        c![@construct[1] a.push($e), for $($rest)*];
+        a.finish()
+    }};
+    (@construct[0] {{join [$sep:expr] [$prefix:expr] [$suffix:expr]} [] $cap:tt [] []} $e:expr, for $($rest:tt)*) => {{
+        let mut j = $crate::__private::Joiner::new($sep);
+        let mut s = std::string::ToString::to_string(&$prefix);
+        c!(@reserve s $cap);
+        c![@construct[1] j.push(&mut s, $e), for $($rest)*];
+        s += &std::string::ToString::to_string(&$suffix);
+        s
+    }};
+    (@construct[0] {{display [$sep:expr] [$prefix:expr] [$suffix:expr]} [] $cap:tt [] []} $e:expr, for $($rest:tt)*) => {{
+        $crate::__private::display(|f| {
+            let mut j = $crate::__private::Joiner::new($sep);
+            f.write_fmt(format_args!("{}", $prefix))?;
+            c![@construct[1] {j.fmt(f, $e)?;}, for $($rest)*];
+            f.write_fmt(format_args!("{}", $suffix))
+        })
+    }};
+    (@construct[0] {{$mode:ident [$sep:expr] [$prefix:expr] [$suffix:expr]} [] $cap:tt [] []} $($rest:tt)*) => {{
+        compile_error!(concat!("`", stringify!($mode), "` requires an expression body without a `:`"))
+    }};
+    (@construct[0] $opts:tt @entry $($rest:tt)*) => {{
+        compile_error!("This loop body requires a map collection")
+    }};
+    (@construct[0] $opts:tt $s:stmt;, for $($rest:tt)*) => {{
+        compile_error!("Statement comprehensions can't have an output collection")
+    }};
+    (@construct[0] $($rest:tt)*) => {{
+        compile_error!("Invalid comprehension")
+    }};
+
+    // Construct a lazy iterator from the loops and if-expressions. The first
+    // group holds the capture mode of the outermost closure; the inner ones
+    // always move the earlier loop variables.
+    (@iter $mv:tt $e:expr, for @await $($rest:tt)*) => {
+        compile_error!("`for await` can't be used in a lazy, buffered or parallel comprehension")
+    };
+    (@iter [$($mv:tt)?] $e:expr, for $el:ident in $iter:expr $(, $($rest:tt)*)?) => {
+        IntoIterator::into_iter($iter).flat_map($($mv)? |$el| c!(@iter [move] $e $(, $($rest)*)?))
+    };
+    (@iter [$($mv:tt)?] $e:expr, for $(@$strict:ident)? $p:pat in $iter:expr $(, $($rest:tt)*)?) => {
+        IntoIterator::into_iter($iter).flat_map($($mv)? |item| {
+            Option::into_iter(match item {
+                $p => Some(c!(@iter [move] $e $(, $($rest)*)?)),
+                #[allow(unreachable_patterns)]
+                _ => c!(@mismatch [$($strict)?] $p, None),
+            })
+            .flatten()
+        })
+    };
+    (@iter $mv:tt $e:expr, for $($rest:tt)*) => {
+        compile_error!("Invalid for-loop")
+    };
+    (@iter $mv:tt $e:expr, if let $p:pat = $val:expr $(, $($rest:tt)*)?) => {
+        Option::into_iter(if let $p = $val { Some(c!(@iter $mv $e $(, $($rest)*)?)) } else { None }).flatten()
+    };
+    (@iter $mv:tt $e:expr, if $cond:expr $(, $($rest:tt)*)?) => {
+        Option::into_iter(if $cond { Some(c!(@iter $mv $e $(, $($rest)*)?)) } else { None }).flatten()
+    };
+    (@iter $mv:tt $e:expr, if $($rest:tt)*) => {
+        compile_error!("Invalid if-expression")
+    };
+    (@iter [$($mv:tt)?] $e:expr, let $p:pat = $val:expr $(, $($rest:tt)*)?) => {
+        std::iter::once($val).flat_map($($mv)? |$p| c!(@iter [move] $e $(, $($rest)*)?))
+    };
+    (@iter $mv:tt $e:expr, let $($rest:tt)*) => {
+        compile_error!("Invalid let binding")
+    };
+    (@iter $mv:tt $e:expr, while $($rest:tt)*) => {
+        compile_error!("`while` can't end the loop of a lazy, buffered or parallel comprehension")
+    };
+    (@iter $mv:tt $e:expr, until $($rest:tt)*) => {
+        compile_error!("`until` can't end a lazy, buffered or parallel comprehension")
+    };
+    (@iter $mv:tt $e:expr) => {
+        std::iter::once($e)
+    };
+
+    // Collect a parallel iterator over the outermost loop into `$t`.
+    (@par $t:tt $e:expr, for @await $($rest:tt)*) => {
+        compile_error!("`for await` can't be used in a lazy, buffered or parallel comprehension")
+    };
+    (@par [$t:ty] $e:expr, for $(@$strict:ident)? $p:pat in $iter:expr $(, $($rest:tt)*)?) => {{
+        let iter = $crate::__comprende_rayon!(iter::IntoParallelIterator::into_par_iter)($iter);
+        let iter = $crate::__comprende_rayon!(iter::ParallelIterator::flat_map_iter)(iter, |item| {
+            Option::into_iter(match item {
+                $p => Some(c!(@iter [move] $e $(, $($rest)*)?)),
+                #[allow(unreachable_patterns)]
+                _ => c!(@mismatch [$($strict)?] $p, None),
+            })
+            .flatten()
+        });
+        $crate::__comprende_rayon!(iter::ParallelIterator::collect::<$t>)(iter)
+    }};
+    (@par $t:tt $e:expr, for $($rest:tt)*) => {
+        compile_error!("Invalid for-loop")
+    };
+
+    // Reserve space in a collection if a capacity was given.
+    (@reserve $c:ident []) => {};
+    (@reserve $c:ident [$n:expr]) => { $c.reserve($n); };
+
+    // Insert an entry into a map according to the duplicate-key policy.
+    (@insert $m:ident [$(last)?] $k:expr, $v:expr) => {
+        $m.insert($k, $v);
+    };
+    (@insert $m:ident [first] $k:expr, $v:expr) => {
+        let k = $k;
+        if !$m.contains_key(&k) {
+            $m.insert(k, $v);
+        }
+    };
+    (@insert $m:ident [panic] $k:expr, $v:expr) => {
+        let k = $k;
+        if $m.contains_key(&k) {
+            panic!("duplicate key in map comprehension: {:?}", k);
+        }
+        $m.insert(k, $v);
+    };
+    (@insert $m:ident [error $label:lifetime] $k:expr, $v:expr) => {
+        let k = $k;
+        if $m.contains_key(&k) {
+            break $label Err($crate::DuplicateKey(k));
+        }
+        $m.insert(k, $v);
+    };
+
+    // Insert a rewritten map body (see @preprocess[2]). The group after
+    // `@entry` is the kind of map being built, or `_` if its type was given,
+    // and decides the types of new groups and inner maps.
+    (@insert $m:ident $dup:tt @entry $kind:tt [$k:expr] {set [$v:expr]}) => {
+        c!(@insert $m $dup $k, $v);
+    };
+    (@insert $m:ident $dup:tt @entry {_} [$k:expr] {nest $k2:tt $how:tt}) => {{
+        use $crate::__private::Map as _;
+        let inner = $m.value_or_insert_with($k, Default::default);
+        c!(@insert inner $dup @entry {_} $k2 $how);
+    }};
+    (@insert $m:ident $dup:tt @entry {$($kind:ident)*} [$k:expr] {nest $k2:tt $how:tt}) => {{
+        use $crate::__private::Map as _;
+        let inner = $m.value_or_insert_with($k, || c!(@new {$($kind)* map} []));
+        c!(@insert inner $dup @entry {$($kind)*} $k2 $how);
+    }};
+    // Fold a value into the entry for its key. Operators start from the
+    // default value, like a Python `defaultdict`.
+    (@insert $m:ident [] @entry $kind:tt [$k:expr] {update [$op:tt] [$v:expr]}) => {{
+        use $crate::__private::Map as _;
+        let (k, v) = ($k, $v);
+        let slot = $m.value_or_insert_with(k, || $crate::__private::default_like(&v));
+        *slot $op v;
+    }};
+    (@insert $m:ident [] @entry $kind:tt [$k:expr] {merge [$v:expr] [$f:expr]}) => {{
+        use $crate::__private::Map as _;
+        $m.merge($k, $v, $f);
+    }};
+    // Add a value to the group for its key.
+    (@insert $m:ident [] @entry {_} [$k:expr] {group [$v:expr] []}) => {{
+        use $crate::__private::Map as _;
+        Extend::extend($m.value_or_insert_with($k, Default::default), std::iter::once($v));
+    }};
+    (@insert $m:ident [] @entry $kind:tt [$k:expr] {group [$v:expr] []}) => {{
+        use $crate::__private::Map as _;
+        $m.value_or_insert_with($k, Vec::new).push($v);
+    }};
+    (@insert $m:ident [] @entry $kind:tt [$k:expr] {group [$v:expr] [$t:ty]}) => {{
+        use $crate::__private::Map as _;
+        Extend::extend($m.value_or_insert_with($k, <$t as Default>::default), std::iter::once($v));
+    }};
+    (@insert $m:ident $dup:tt @entry $($rest:tt)*) => {
+        compile_error!("`on_duplicate` requires a `k: v` loop body")
+    };
+
+    // Create an empty map or set, using the hasher if one was given.
+    (@new {map} []) => { std::collections::HashMap::new() };
+    (@new {map} [$h:expr]) => { std::collections::HashMap::with_hasher($h) };
+    (@new {set} []) => { std::collections::HashSet::new() };
+    (@new {set} [$h:expr]) => { std::collections::HashSet::with_hasher($h) };
+    (@new {ordered map} []) => { $crate::__comprende_indexmap!(IndexMap::new()) };
+    (@new {ordered map} [$h:expr]) => { $crate::__comprende_indexmap!(IndexMap::with_hasher($h)) };
+    (@new {ordered set} []) => { $crate::__comprende_indexmap!(IndexSet::new()) };
+    (@new {ordered set} [$h:expr]) => { $crate::__comprende_indexmap!(IndexSet::with_hasher($h)) };
 
-    // Construct the for-loops and if-expressions.
-    (@construct[1] $s:stmt, for $el:ident in $iter:expr $(, $($rest:tt)*)?) => {{
-        for $el in $iter {
-            c![@construct[1] $s $(, $($rest)*)?]
+    // Construct the for-loops and if-expressions. The comprehension is a
+    // labeled block so that `until` clauses can end it.
+    (@construct[1] $s:stmt, $($rest:tt)*) => {{
+        #[allow(unused_labels)]
+        'all: {
+            c![@construct[2] ['all] [] $s, $($rest)*]
         }
     }};
-    (@construct[1] $s:stmt, for $p:pat in $iter:expr $(, $($rest:tt)*)?) => {{
-        for $p in $iter {
-            c![@construct[1] $s $(, $($rest)*)?]
+
+    // The first group holds the label of the comprehension and the second
+    // the label of the innermost loop, which `while` clauses break out of.
+    // Either is empty when the loop bodies run in a closure.
+    (@construct[2] $all:tt $l:tt $s:stmt, for @await $(@$strict:ident)? $p:pat in $stream:expr $(, $($rest:tt)*)?) => {{
+        let mut stream = std::pin::pin!($stream);
+        #[allow(unused_labels)]
+        'l: while let Some(item) = $crate::__comprende_futures!(StreamExt::next)(&mut stream).await {
+            #[allow(clippy::collapsible_match)]
+            match item {
+                $p => {
+                    c![@construct[2] $all ['l] $s $(, $($rest)*)?]
+                }
+                #[allow(unreachable_patterns)]
+                _ => c!(@mismatch [$($strict)?] $p, ()),
+            }
+        }
+    }};
+    (@construct[2] $all:tt $l:tt $s:stmt, for $el:ident in $iter:expr $(, $($rest:tt)*)?) => {{
+        #[allow(unused_labels)]
+        'l: for $el in $iter {
+            c![@construct[2] $all ['l] $s $(, $($rest)*)?]
+        }
+    }};
+    (@construct[2] $all:tt $l:tt $s:stmt, for $(@$strict:ident)? $p:pat in $iter:expr $(, $($rest:tt)*)?) => {{
+        #[allow(unused_labels)]
+        'l: for item in $iter {
+            #[allow(clippy::collapsible_match)]
+            match item {
+                $p => {
+                    c![@construct[2] $all ['l] $s $(, $($rest)*)?]
+                }
+                #[allow(unreachable_patterns)]
+                _ => c!(@mismatch [$($strict)?] $p, ()),
+            }
         }
     }};
-    (@construct[1] $s:stmt, for $($rest:tt)*) => {{
+    (@construct[2] $all:tt $l:tt $s:stmt, for $($rest:tt)*) => {{
         compile_error!("Invalid for-loop")
     }};
-    (@construct[1] $s:stmt, if $cond:expr $(, $($rest:tt)*)?) => {{
+    (@construct[2] $all:tt $l:tt $s:stmt, if let $p:pat = $val:expr $(, $($rest:tt)*)?) => {{
+        if let $p = $val {
+            c![@construct[2] $all $l $s $(, $($rest)*)?]
+        }
+    }};
+    (@construct[2] $all:tt $l:tt $s:stmt, if $cond:expr $(, $($rest:tt)*)?) => {{
         if $cond {
-            c![@construct[1] $s $(, $($rest)*)?]
+            c![@construct[2] $all $l $s $(, $($rest)*)?]
         }
     }};
-    (@construct[1] $s:stmt, if $($rest:tt)*) => {{
+    (@construct[2] $all:tt $l:tt $s:stmt, if $($rest:tt)*) => {{
         compile_error!("Invalid if-expression")
     }};
-    (@construct[1] $s:stmt) => {{
+    (@construct[2] $all:tt $l:tt $s:stmt, let $p:pat = $val:expr $(, $($rest:tt)*)?) => {{
+        let $p = $val;
+        c![@construct[2] $all $l $s $(, $($rest)*)?]
+    }};
+    (@construct[2] $all:tt $l:tt $s:stmt, let $($rest:tt)*) => {{
+        compile_error!("Invalid let binding")
+    }};
+    (@construct[2] $all:tt [$l:lifetime] $s:stmt, while $cond:expr $(, $($rest:tt)*)?) => {{
+        if !$cond {
+            break $l;
+        }
+        c![@construct[2] $all [$l] $s $(, $($rest)*)?]
+    }};
+    (@construct[2] $all:tt [] $s:stmt, while $cond:expr $(, $($rest:tt)*)?) => {{
+        compile_error!("`while` can't end the loop of a parallel or in-place comprehension")
+    }};
+    (@construct[2] $all:tt $l:tt $s:stmt, while $($rest:tt)*) => {{
+        compile_error!("Invalid while-expression")
+    }};
+    (@construct[2] [$all:lifetime] $l:tt $s:stmt, until $cond:expr $(, $($rest:tt)*)?) => {{
+        if $cond {
+            break $all;
+        }
+        c![@construct[2] [$all] $l $s $(, $($rest)*)?]
+    }};
+    (@construct[2] [] $l:tt $s:stmt, until $cond:expr $(, $($rest:tt)*)?) => {{
+        compile_error!("`until` can't end a parallel or in-place comprehension")
+    }};
+    (@construct[2] $all:tt $l:tt $s:stmt, until $($rest:tt)*) => {{
+        compile_error!("Invalid until-expression")
+    }};
+    (@construct[2] $all:tt $l:tt $s:stmt) => {{
         $s
     }};
 
+    // Handle an item that doesn't match its `for` pattern: skip it, or panic
+    // in a strict comprehension.
+    (@mismatch [] $p:pat, $skip:expr) => {
+        $skip
+    };
+    (@mismatch [strict] $p:pat, $skip:expr) => {
+        panic!(concat!("item doesn't match the pattern `", stringify!($p), "` in a strict comprehension"))
+    };
+
     // Public entry point
     ($($comp:tt)*) => {{
-        c!(@preprocess[0] {$($comp)*} {})
+        c!(@preprocess[0] {{} [] [] [] []} {$($comp)*} {})
     }};
 }
 
+// Expands to a path inside the `indexmap` crate, or to an error if the
+// `indexmap` feature is disabled.
+#[cfg(feature = "indexmap")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __comprende_indexmap {
+    ($($path:tt)*) => {
+        $crate::__private::indexmap::$($path)*
+    };
+}
+
+#[cfg(not(feature = "indexmap"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __comprende_indexmap {
+    ($($path:tt)*) => {
+        compile_error!("`ordered` comprehensions require the `indexmap` feature")
+    };
+}
+
+// Expands to a path inside the `futures` crate (or to async support code
+// in `__private`), or to an error if the `futures` feature is disabled.
+#[cfg(feature = "futures")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __comprende_futures {
+    (@private $($path:tt)*) => {
+        $crate::__private::$($path)*
+    };
+    ($($path:tt)*) => {
+        $crate::__private::futures::$($path)*
+    };
+}
+
+#[cfg(not(feature = "futures"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __comprende_futures {
+    ($($path:tt)*) => {
+        compile_error!("Async comprehensions require the `futures` feature")
+    };
+}
+
+// Expands to a path inside the `rayon` crate, or to an error if the `rayon`
+// feature is disabled.
+#[cfg(feature = "rayon")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __comprende_rayon {
+    ($($path:tt)*) => {
+        $crate::__private::rayon::$($path)*
+    };
+}
+
+#[cfg(not(feature = "rayon"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __comprende_rayon {
+    ($($path:tt)*) => {
+        compile_error!("`par` comprehensions require the `rayon` feature")
+    };
+}
+
 #[cfg(test)]
 mod tests {
     // Vector
@@ -248,6 +1833,277 @@ mod tests {
         );
     }
 
+    #[test]
+    fn if_let_vec() {
+        let words = ["1", "two", "3", "-4"];
+        let v = c![n for w in &words if let Ok(n) = w.parse::<i32>()];
+        assert_eq!(v, vec![1, 3, -4]);
+
+        let v = c![
+            (w, n) for w in &words
+            if let Ok(n) = w.parse::<i32>()
+            if let Some(n) = n.checked_sub(2)
+            if n >= 0
+        ];
+        assert_eq!(v, vec![(&"3", 1)]);
+
+        let v = c![(n, i) for w in &words if let Ok(n) = w.parse::<u8>() for i in 0..n];
+        assert_eq!(v, vec![(1, 0), (3, 0), (3, 1), (3, 2)]);
+
+        let v: Vec<_> = c![iter; n * 2 for w in &words if let Ok(n) = w.parse::<i32>()].collect();
+        assert_eq!(v, vec![2, 6, -8]);
+    }
+
+    #[test]
+    fn let_vec() {
+        let words = ["1", "two", "3"];
+        let v = c![n for w in &words let n = w.parse::<i32>() if n.is_ok()];
+        assert_eq!(v, vec![Ok(1), Ok(3)]);
+
+        let v = c![(x, y, s) for x in 1..=3 let s = x * x for y in 0..s if y % 4 == 3];
+        assert_eq!(v, vec![(2, 3, 4), (3, 3, 9), (3, 7, 9)]);
+
+        let v = c![a + b for p in &[(1, 2), (3, 4)] let &(a, b) = p let (a, b) = (b, a) if a > 2];
+        assert_eq!(v, vec![7]);
+
+        let v: Vec<_> = c![iter; s for x in 1..=3 let s = x.to_string() if s != "2"].collect();
+        assert_eq!(v, vec!["1", "3"]);
+
+        let v = c![y for x in 0..5 let y = if x % 2 == 0 { x } else { -x }];
+        assert_eq!(v, vec![0, -1, 2, -3, 4]);
+
+        let v = c![y for x in 0..4 let y = if x == 0 { 0 } else if let 1 = x { 1 } else { -x } if y != 1];
+        assert_eq!(v, vec![0, -2, -3]);
+    }
+
+    #[test]
+    fn refutable_for_vec() {
+        let opts = [Some(1), None, Some(3)];
+        assert_eq!(c![x for Some(x) in opts], vec![1, 3]);
+        assert_eq!(c![x for &Some(x) in &opts], vec![1, 3]);
+
+        let results: [Result<i32, &str>; 3] = [Ok(1), Err("no"), Ok(2)];
+        let v = c![(x, y) for Ok(x) in results for (y, Some(_)) in [(1, Some(())), (2, None)]];
+        assert_eq!(v, vec![(1, 1), (2, 1)]);
+
+        let v: Vec<_> = c![iter; x * 10 for Some(x) in opts].collect();
+        assert_eq!(v, vec![10, 30]);
+
+        assert_eq!(c![strict; x for Some(x) in [Some(1), Some(2)]], vec![1, 2]);
+        assert_eq!(c![strict; x + y for (x, y) in [(1, 2)]], vec![3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "item doesn't match the pattern `Some(x)` in a strict comprehension")]
+    fn refutable_for_strict() {
+        let opts = [Some(1), None, Some(3)];
+        c![strict; x for Some(x) in opts];
+    }
+
+    #[test]
+    #[should_panic(expected = "item doesn't match the pattern `Some(x)` in a strict comprehension")]
+    fn refutable_for_strict_iter() {
+        let opts = [Some(1), None, Some(3)];
+        let _ = c![strict; iter; x for y in 0..2 for Some(x) in opts.map(|o| o.map(|x| x + y))].count();
+    }
+
+    #[test]
+    fn zip_vec() {
+        let xs = [1, 2, 3];
+        let ys = ['a', 'b'];
+        assert_eq!(c![(x, y) for x in xs zip y in ys], vec![(1, 'a'), (2, 'b')]);
+        assert_eq!(
+            c![x * y * z for &x in &xs zip y in 1.. zip z in [10, 100, 1000]],
+            vec![10, 400, 9000]
+        );
+        assert_eq!(
+            c![(x, y, z) for x in 0..2 for y in 0..2 zip z in 'a'.. if x != y],
+            vec![(0, 1, 'b'), (1, 0, 'a')]
+        );
+        assert_eq!(c![x for Some(x) in [Some(1), None] zip _ in 0..], vec![1]);
+        assert_eq!(
+            c![a.zip(b) for a in xs.iter().zip(&ys).map(|(&x, _)| Some(x)) zip b in [Some(1)]],
+            vec![Some((1, 1))]
+        );
+
+        assert_eq!(c![zip(shortest); (x, y) for x in xs zip y in ys], vec![(1, 'a'), (2, 'b')]);
+        assert_eq!(
+            c![zip(longest(0, '-')); (x, y) for x in xs zip y in ys],
+            vec![(1, 'a'), (2, 'b'), (3, '-')]
+        );
+        assert_eq!(
+            c![zip(longest(0, '-', "")); (x, y, z) for x in 0..1 zip y in ys zip z in ["s"; 3]],
+            vec![(0, 'a', "s"), (0, 'b', "s"), (0, '-', "s")]
+        );
+        assert_eq!(c![zip(strict); x + y for x in xs zip y in xs], vec![2, 4, 6]);
+
+        // `zip` is only a clause after the source of a `for`
+        use std::iter::zip;
+        assert_eq!(c![a + b for (a, b) in zip(xs, xs)], vec![2, 4, 6]);
+        assert_eq!(c![a * b for (a, b) in zip(xs, 1..) zip _ in 0..2], vec![1, 4]);
+        assert_eq!(c![zip(strict); x for x in 0..3 if zip(xs, xs).count() > x], vec![0, 1, 2]);
+        assert_eq!(c![(x, y) for x in 1.. zip (y, _) in zip(ys, 0..)], vec![(1, 'a'), (2, 'b')]);
+    }
+
+    #[test]
+    #[should_panic(expected = "zipped iterators have different lengths")]
+    fn zip_strict() {
+        c![zip(strict); (x, y) for x in [1, 2, 3] zip y in ['a', 'b']];
+    }
+
+    #[test]
+    fn step_int() {
+        assert_eq!(c![x for x in 0..10 step 3], vec![0, 3, 6, 9]);
+        assert_eq!(c![x for x in 0..9 step 3], vec![0, 3, 6]);
+        assert_eq!(c![x for x in 0..=9 step 3], vec![0, 3, 6, 9]);
+        assert_eq!(c![x for x in 10..0 step -2], vec![10, 8, 6, 4, 2]);
+        assert_eq!(c![x for x in 10..=0 step -2], vec![10, 8, 6, 4, 2, 0]);
+        assert_eq!(c![x for x in 0..10 step -1], Vec::<i32>::new());
+        assert_eq!(c![x for x in 5..5 step 1], Vec::<i32>::new());
+        assert_eq!(c![x for x in 5..=5 step 1], vec![5]);
+
+        let n: usize = 7;
+        assert_eq!(c![i for i in 0..n step 2], vec![0, 2, 4, 6]);
+        assert_eq!(c![x for x in 250u8..=255 step 5], vec![250, 255]);
+        assert_eq!(
+            c![(x, y) for x in 0..4 step 2 zip y in 10..=0 step -5],
+            vec![(0, 10), (2, 5)]
+        );
+        assert_eq!(c![iter; x for x in -3..3 step 2].collect::<Vec<_>>(), vec![-3, -1, 1]);
+
+        // `step` is only a clause after the source of a `for`
+        let step = 2;
+        assert_eq!(c![x for x in 0..10 if x % step == 0], vec![0, 2, 4, 6, 8]);
+        assert_eq!(c![x for x in 0..step], vec![0, 1]);
+        assert_eq!(c![step for x in 0..10 step step let step = x * step], vec![0, 4, 8, 12, 16]);
+    }
+
+    #[test]
+    #[should_panic(expected = "range step can't be zero")]
+    fn step_zero() {
+        c![x for x in 0..10 step 0];
+    }
+
+    #[test]
+    fn step_float() {
+        assert_eq!(c![x for x in 0.0..0.3 step 0.1], vec![0.0, 0.1, 0.2]);
+        assert_eq!(c![x for x in 0.0..=0.3 step 0.1], vec![0.0, 0.1, 0.2, 0.3]);
+        assert_eq!(c![x for x in 0.0..=0.35 step 0.1], vec![0.0, 0.1, 0.2, 0.30000000000000004]);
+        assert_eq!(c![x for x in 1.0..=0.0 step -0.25], vec![1.0, 0.75, 0.5, 0.25, 0.0]);
+        assert_eq!(c![x for x in 1.0..0.0 step -0.25], vec![1.0, 0.75, 0.5, 0.25]);
+        assert_eq!(c![x for x in 0.0..1.0 step -0.1], Vec::<f64>::new());
+
+        // Accumulating `x += 0.1` would end at 0.9999999999999999 here
+        let v = c![x for x in 0.0..=1.0 step 0.1];
+        assert_eq!(v.len(), 11);
+        assert_eq!(v[3], 3.0 * 0.1);
+        assert_eq!(v[10], 1.0);
+
+        let v = c![x for x in 0.0f32..1.0 step 0.1];
+        assert_eq!(v.len(), 10);
+    }
+
+    #[test]
+    fn linspace() {
+        assert_eq!(c![x for x in linspace(0.0, 1.0, 5)], vec![0.0, 0.25, 0.5, 0.75, 1.0]);
+        assert_eq!(c![x for x in linspace(1.0, 0.0, 3)], vec![1.0, 0.5, 0.0]);
+        assert_eq!(c![x for x in linspace(2.0, 3.0, 1)], vec![2.0]);
+        assert_eq!(c![x for x in linspace(2.0, 3.0, 0)], Vec::<f64>::new());
+
+        let v = c![x for x in linspace(0.0, 0.3, 4)];
+        assert_eq!(v.len(), 4);
+        assert_eq!(v[3], 0.3);
+        assert_eq!(
+            c![(i, x) for i in 0.. zip x in linspace(-1.0f32, 1.0, 3)],
+            vec![(0, -1.0), (1, 0.0), (2, 1.0)]
+        );
+    }
+
+    #[test]
+    fn slice() {
+        let v: Vec<i32> = (0..10).collect();
+        let xs = &v;
+        assert_eq!(c![*x for x in xs[1:-1:2]], vec![1, 3, 5, 7]);
+        assert_eq!(c![*x for x in xs[7:]], vec![7, 8, 9]);
+        assert_eq!(c![*x for x in xs[:3]], vec![0, 1, 2]);
+        assert_eq!(c![*x for x in xs[-2:]], vec![8, 9]);
+        assert_eq!(c![*x for x in xs[::4]], vec![0, 4, 8]);
+        assert_eq!(c![*x for x in xs[::-3]], vec![9, 6, 3, 0]);
+        assert_eq!(c![*x for x in xs[5:1:-2]], vec![5, 3]);
+        assert_eq!(c![*x for x in xs[-1:-4:-1]], vec![9, 8, 7]);
+        assert_eq!(c![*x for x in xs[-20:2]], vec![0, 1]);
+        assert_eq!(c![*x for x in xs[3:20:3]], vec![3, 6, 9]);
+        assert_eq!(c![*x for x in xs[6:2]], Vec::<i32>::new());
+        assert_eq!(c![*x for x in xs[2:6:-1]], Vec::<i32>::new());
+
+        let (start, end, step) = (1usize, -1, 3i64);
+        assert_eq!(c![*x for x in xs[start:end:step]], vec![1, 4, 7]);
+        assert_eq!(c![x for x in xs.iter().map(|x| x * 2)[-3:]], vec![14, 16, 18]);
+        assert_eq!(c![x for x in (0..).filter(|x| x % 3 == 0)[2:5]], vec![6, 9, 12]);
+        assert_eq!(c![x for x in "a:b:c".split(':')[1:]], vec!["b", "c"]);
+        let mut last = c![iter; x for x in (0..u32::MAX)[::-1]];
+        assert_eq!((last.next(), last.next()), (Some(u32::MAX - 1), Some(u32::MAX - 2)));
+
+        let rows = [[1, 2, 3], [4, 5, 6]];
+        assert_eq!(c![(x, y) for x in rows[1][::2] for y in 0..1], vec![(4, 0), (6, 0)]);
+        assert_eq!(c![*x for x in xs[usize::MIN:2]], vec![0, 1]);
+        assert_eq!(c![*x for x in &xs[..2]], vec![0, 1]);
+
+        // Brackets that can't be a slice are left alone
+        #[derive(Debug, PartialEq)]
+        enum E {
+            A,
+            B,
+        }
+        assert_eq!(c![x for x in vec![E::A, E::B]], vec![E::A, E::B]);
+        assert_eq!(c![x for x in [std::f64::consts::PI, 1.0]], vec![std::f64::consts::PI, 1.0]);
+        assert_eq!(c![f(1) for f in [|a: i32| a + 1]], vec![2]);
+        assert_eq!(c![x for x in [[1, 2], [3, 4]][1]], vec![3, 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "negative slice indices and steps need a source with a known length")]
+    fn slice_unsized() {
+        let end = -1;
+        let _ = c![x for x in (0..10).filter(|x| x % 2 == 0)[:end]];
+    }
+
+    #[test]
+    fn while_vec() {
+        let v = c![x * x for x in 1.. while x * x < 50 if x % 2 == 1];
+        assert_eq!(v, vec![1, 9, 25, 49]);
+
+        let v = c![(x, y) for x in 1..=3 for y in 1.. while y <= x];
+        assert_eq!(v, vec![(1, 1), (2, 1), (2, 2), (3, 1), (3, 2), (3, 3)]);
+
+        let v = c![(x, y) for x in 1..=3 if x != 2 for y in 1.. let z = x * y while z < 6];
+        assert_eq!(v, vec![(1, 1), (1, 2), (1, 3), (1, 4), (1, 5), (3, 1)]);
+    }
+
+    #[test]
+    fn until_vec() {
+        let v = c![(x, y) for x in 1.. for y in 0..x until x * y > 6];
+        assert_eq!(v, vec![(1, 0), (2, 0), (2, 1), (3, 0), (3, 1), (3, 2), (4, 0), (4, 1)]);
+
+        let v = c![x for x in 1.. break if x > 3];
+        assert_eq!(v, vec![1, 2, 3]);
+
+        let m = c! {x: x * x for x in 1.. until x > 2};
+        assert_eq!(m, std::collections::HashMap::from([(1, 1), (2, 4)]));
+
+        let mut n = 0;
+        c!(n += x; for x in 1.. until n > 10);
+        assert_eq!(n, 15);
+
+        // `until` is only a clause after an expression
+        let until = 3;
+        assert_eq!(c![x for x in 0..10 if x < until], vec![0, 1, 2]);
+        assert_eq!(c![x for x in 0..until], vec![0, 1, 2]);
+        assert_eq!(c![x for x in 0..until if x > 0 until x > 1], vec![1]);
+        assert_eq!(c![x for x in until.. until x > until + 1], vec![3, 4]);
+        assert_eq!(c![y for _ in 0..1 let y = until], vec![3]);
+    }
+
     // HashMap
     #[test]
     fn simple_map() {
@@ -344,6 +2200,721 @@ mod tests {
         );
     }
 
+    // HashSet
+    #[test]
+    fn simple_set() {
+        let s = c! {set; x % 4 for x in 1..=10};
+        assert_eq!(s, [0, 1, 2, 3].iter().cloned().collect());
+    }
+
+    #[test]
+    fn simple_cond_set() {
+        let s = c! {set; x % 4 for x in 1..=10 if x % 2 == 0};
+        assert_eq!(s, [0, 2].iter().cloned().collect());
+    }
+
+    #[test]
+    fn for_for_set() {
+        let s = c! {set; format!("{}|{}", x % 2, y) for x in 1..=3 for y in 'a'..='c'};
+        assert_eq!(
+            s,
+            [
+                "0|a".to_string(),
+                "0|b".to_string(),
+                "0|c".to_string(),
+                "1|a".to_string(),
+                "1|b".to_string(),
+                "1|c".to_string(),
+            ]
+            .iter()
+            .cloned()
+            .collect()
+        );
+    }
+
+    #[test]
+    fn for_for_if_set() {
+        let s = c! {set; format!("{}|{}", x % 2, y) for x in 1..=3 for y in 'a'..='c' if y != 'b'};
+        assert_eq!(
+            s,
+            [
+                "0|a".to_string(),
+                "0|c".to_string(),
+                "1|a".to_string(),
+                "1|c".to_string(),
+            ]
+            .iter()
+            .cloned()
+            .collect()
+        );
+    }
+
+    #[test]
+    fn for_if_for_set() {
+        let s = c! {set; format!("{}|{}", x % 2, y) for x in 1..=3 if x != 2 for y in 'a'..='c'};
+        assert_eq!(
+            s,
+            [
+                "1|a".to_string(),
+                "1|b".to_string(),
+                "1|c".to_string(),
+            ]
+            .iter()
+            .cloned()
+            .collect()
+        );
+    }
+
+    // Ordered
+    #[cfg(feature = "indexmap")]
+    #[test]
+    fn ordered_map() {
+        let m = c! {ordered; x % 7: x * x for x in (1..=10).rev() if x != 5};
+        assert_eq!(
+            m.into_iter().collect::<Vec<_>>(),
+            vec![(3, 9), (2, 4), (1, 1), (0, 49), (6, 36), (4, 16)]
+        );
+    }
+
+    #[cfg(feature = "indexmap")]
+    #[test]
+    fn ordered_set() {
+        let s = c! {set; ordered; (x * y) % 5 for x in (1..=3).rev() for y in 1..=3};
+        assert_eq!(s.into_iter().collect::<Vec<_>>(), vec![3, 1, 4, 2]);
+
+        let s = c! {ordered; set; x % 3 for x in 1..=10};
+        assert_eq!(s.into_iter().collect::<Vec<_>>(), vec![1, 2, 0]);
+    }
+
+    // Hasher
+    #[test]
+    fn map_with_hasher() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::collections::HashMap;
+        use std::hash::BuildHasherDefault;
+
+        type Fixed = BuildHasherDefault<DefaultHasher>;
+
+        let m: HashMap<_, _, Fixed> = c! {with_hasher(Fixed::default()); x: x * x for x in 1..=5 if x != 3};
+        assert_eq!(
+            m,
+            [(1, 1), (2, 4), (4, 16), (5, 25)].iter().cloned().collect()
+        );
+
+        let m = c! {HashMap<_, _, Fixed>; x: x * x for x in 1..=5 if x != 3};
+        assert_eq!(
+            m,
+            [(1, 1), (2, 4), (4, 16), (5, 25)].iter().cloned().collect()
+        );
+    }
+
+    #[test]
+    fn set_with_hasher() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::collections::HashSet;
+        use std::hash::BuildHasherDefault;
+
+        type Fixed = BuildHasherDefault<DefaultHasher>;
+
+        let s: HashSet<_, Fixed> = c! {set; with_hasher(Fixed::default()); x % 4 for x in 1..=10};
+        assert_eq!(s, [0, 1, 2, 3].iter().cloned().collect());
+    }
+
+    #[cfg(feature = "indexmap")]
+    #[test]
+    fn ordered_with_hasher() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        type Fixed = BuildHasherDefault<DefaultHasher>;
+
+        let m: indexmap::IndexMap<_, _, Fixed> =
+            c! {with_hasher(Fixed::default()); ordered; x: x * x for x in (1..=5).rev()};
+        assert_eq!(
+            m.into_iter().collect::<Vec<_>>(),
+            vec![(5, 25), (4, 16), (3, 9), (2, 4), (1, 1)]
+        );
+    }
+
+    // Capacity
+    #[test]
+    fn size_hint_capacity() {
+        let v = c![x for x in 0..1000];
+        assert!(v.capacity() >= 1000);
+
+        let m = c! {x: x for x in 0..1000};
+        assert!(m.capacity() >= 1000);
+
+        let s = c! {set; x for x in vec![1; 1000]};
+        assert!(s.capacity() >= 1000);
+
+        // Temporaries in the source live as long as the loop
+        let s = "AbA";
+        assert_eq!(c![ch for ch in s.to_lowercase().chars()], vec!['a', 'b', 'a']);
+        assert_eq!(c! {c: 1 for c in s.to_lowercase().chars()}.len(), 2);
+    }
+
+    #[test]
+    fn explicit_capacity() {
+        let v = c![with_capacity(100); x * y for x in 0..10 for y in 0..10];
+        assert!(v.capacity() >= 100);
+        assert_eq!(v.len(), 100);
+
+        let v = c![with_capacity(100); x for x in 0..3];
+        assert!(v.capacity() >= 100);
+
+        let s = c![with_capacity(64); join(", "); x for x in 0..3];
+        assert_eq!(s, "0, 1, 2");
+        assert!(s.capacity() >= 64);
+    }
+
+    // Existing collections
+    #[test]
+    fn into_vec() {
+        let mut v = vec![0, 1];
+        c![into v; x * x for x in 2..=5 if x != 3];
+        assert_eq!(v, vec![0, 1, 4, 16, 25]);
+
+        let r = &mut v;
+        c![into r; x for x in 1..=2];
+        c![into &mut v; -x for x in 1..=2];
+        assert_eq!(v, vec![0, 1, 4, 16, 25, 1, 2, -1, -2]);
+    }
+
+    #[test]
+    fn into_map() {
+        use std::collections::HashMap;
+
+        let mut m: HashMap<_, _> = [(1, 'z')].iter().cloned().collect();
+        c! {into m; x: y for x in 1..=2 for y in 'a'..='b'};
+        assert_eq!(m, [(1, 'b'), (2, 'b')].iter().cloned().collect());
+    }
+
+    #[test]
+    fn into_field() {
+        struct Log {
+            lines: Vec<String>,
+        }
+
+        let mut log = Log { lines: Vec::new() };
+        c![into log.lines; format!("{}{}", x, y) for x in 1..=2 for y in 'a'..='b'];
+        c![into log.lines; with_capacity(10); x.to_string() for x in 3..=4];
+        assert_eq!(log.lines, ["1a", "1b", "2a", "2b", "3", "4"]);
+        assert!(log.lines.capacity() >= 10);
+    }
+
+    #[test]
+    fn in_place_vec() {
+        let mut v = vec![3, -1, 4, -1, 5];
+        c!(in v; x * 2 for &x if x > 0);
+        assert_eq!(v, vec![6, 8, 10]);
+
+        let mut v = vec!["a".to_string(), "bb".to_string(), "ccc".to_string()];
+        let r = &mut v;
+        c!(in r; s.to_uppercase() for s if s.len() > 1 if !s.starts_with('c'));
+        assert_eq!(v, vec!["BB".to_string()]);
+
+        let mut d: std::collections::VecDeque<_> = (1..=5).collect();
+        c!(in d; x * x for x);
+        assert_eq!(d, [1, 4, 9, 16, 25]);
+    }
+
+    #[test]
+    fn in_place_map() {
+        use std::collections::{BTreeMap, HashMap};
+
+        let mut m: HashMap<_, _> = (1..=6).map(|x| (x, x)).collect();
+        c!(in m; v * 10 for (k, v) if k % 2 == 0);
+        assert_eq!(m, [(2, 20), (4, 40), (6, 60)].iter().cloned().collect());
+
+        let mut m: BTreeMap<_, _> = (1..=6).map(|x| (x, x.to_string())).collect();
+        c!(in m; v.repeat(2) for (&k, v) if k > 3);
+        assert_eq!(
+            m.into_iter().collect::<Vec<_>>(),
+            vec![
+                (4, "44".to_string()),
+                (5, "55".to_string()),
+                (6, "66".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_last() {
+        let m = c! {on_duplicate(last); x % 3: x for x in 1..=6};
+        assert_eq!(m, [(1, 4), (2, 5), (0, 6)].iter().cloned().collect());
+    }
+
+    #[test]
+    fn duplicate_first() {
+        let mut evaluated = 0;
+        let m = c! {on_duplicate(first); x % 3: { evaluated += 1; x } for x in 1..=6};
+        assert_eq!(m, [(1, 1), (2, 2), (0, 3)].iter().cloned().collect());
+        assert_eq!(evaluated, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate key in map comprehension: 1")]
+    fn duplicate_panic() {
+        let _ = c! {on_duplicate(panic); x % 3: x for x in 1..=6};
+    }
+
+    #[test]
+    fn duplicate_error() {
+        use crate::DuplicateKey;
+
+        let m = c! {on_duplicate(error); x: x * x for x in 1..=3};
+        assert_eq!(m, Ok([(1, 1), (2, 4), (3, 9)].iter().cloned().collect()));
+
+        let m = c! {on_duplicate(error); x % 3: x for x in 1..=6};
+        assert_eq!(m, Err(DuplicateKey(1)));
+    }
+
+    #[test]
+    fn accumulate_map() {
+        let words = ["a", "b", "a", "c", "a", "b"];
+        let m = c! {w: += 1 for w in words};
+        assert_eq!(m, [("a", 3), ("b", 2), ("c", 1)].iter().cloned().collect());
+
+        let m = c! {k: -= 1 for k in ["a", "a", "a"]};
+        assert_eq!(m, [("a", -3)].iter().cloned().collect());
+
+        let m = c! {x % 3: |= 1 << x for x in 1..=6};
+        assert_eq!(m, [(1, 0b10010), (2, 0b100100), (0, 0b1001000)].iter().cloned().collect());
+    }
+
+    #[test]
+    fn merge_map() {
+        let m = c! {x % 3: merge x with std::cmp::max for x in [5, 1, 9, 4, 3]};
+        assert_eq!(m, [(2, 5), (1, 4), (0, 9)].iter().cloned().collect());
+
+        let m = c! {s.len(): merge s.to_string() with |a, b| a + "," + &b for s in ["ab", "c", "de"]};
+        assert_eq!(
+            m,
+            [(2, "ab,de".to_string()), (1, "c".to_string())]
+                .iter()
+                .cloned()
+                .collect()
+        );
+
+        #[derive(Debug, PartialEq)]
+        struct Total(i32);
+        let m = c! {k: merge Total(v) with |a, b| Total(a.0 + b.0) for (k, v) in [(1, 2), (2, 3), (1, 4)]};
+        assert_eq!(m[&1], Total(6));
+
+        fn merge(a: i32, b: i32) -> i32 {
+            a * 10 + b
+        }
+        let m = c! {x: merge(x, 1) for x in 1..3};
+        assert_eq!(m, [(1, 11), (2, 21)].iter().cloned().collect());
+    }
+
+    #[cfg(feature = "indexmap")]
+    #[test]
+    fn merge_ordered() {
+        let m = c! {ordered; x % 3: merge x with |a, b| a + b for x in 1..=7};
+        assert_eq!(m.into_iter().collect::<Vec<_>>(), vec![(1, 12), (2, 7), (0, 9)]);
+    }
+
+    #[test]
+    fn accumulate_other_maps() {
+        use std::collections::{BTreeMap, HashMap};
+
+        let m = c! {BTreeMap<_, _>; c: += 1 for c in "hello".chars()};
+        assert_eq!(
+            m.into_iter().collect::<Vec<_>>(),
+            vec![('e', 1), ('h', 1), ('l', 2), ('o', 1)]
+        );
+
+        let mut totals = HashMap::new();
+        totals.insert("x", 10);
+        c! {into totals; k: += v for (k, v) in [("x", 1), ("y", 2), ("x", 3)]};
+        assert_eq!(totals, [("x", 14), ("y", 2)].iter().cloned().collect());
+    }
+
+    #[test]
+    fn group_map() {
+        let m = c! {x % 3: group x for x in 1..=7};
+        assert_eq!(
+            m,
+            [(1, vec![1, 4, 7]), (2, vec![2, 5]), (0, vec![3, 6])]
+                .iter()
+                .cloned()
+                .collect()
+        );
+
+        let m = c! {w.len(): group w as std::collections::BTreeSet<_> for w in ["b", "aa", "a", "b"]};
+        assert_eq!(m[&1].iter().collect::<Vec<_>>(), vec![&"a", &"b"]);
+        assert_eq!(m[&2].len(), 1);
+
+        let m = c! {x: group x as f64 as Vec<_> for x in [1, 1]};
+        assert_eq!(m[&1], vec![1.0, 1.0]);
+
+        // `[v]` is still a one-element array, and `group` still a name
+        let m = c! {k: [v] for (k, v) in [(1, 'a'), (1, 'b')]};
+        assert_eq!(m, [(1, ['b'])].iter().cloned().collect());
+        let group = 2;
+        let m = c! {k: group - v for (k, v) in [(1, 1)]};
+        assert_eq!(m[&1], 1);
+    }
+
+    #[test]
+    fn group_other_maps() {
+        use std::collections::{BTreeMap, HashMap, HashSet};
+
+        let m = c! {BTreeMap<_, HashSet<_>>; x % 2 == 0: group x % 3 for x in 1..=6};
+        assert_eq!(m[&false], [1, 0, 2].iter().cloned().collect());
+        assert_eq!(m[&true], [2, 1, 0].iter().cloned().collect());
+
+        let mut m: HashMap<&str, Vec<i32>> = HashMap::new();
+        m.insert("odd", vec![-1]);
+        c! {into m; if x % 2 == 0 { "even" } else { "odd" }: group x for x in 1..=4};
+        assert_eq!(m["odd"], vec![-1, 1, 3]);
+        assert_eq!(m["even"], vec![2, 4]);
+    }
+
+    #[test]
+    fn nested_map() {
+        use std::collections::HashMap;
+
+        let m = c! {x % 2: x % 3: x for x in 1..=6};
+        let expected: HashMap<_, HashMap<_, _>> = [
+            (1, [(1, 1), (0, 3), (2, 5)].iter().cloned().collect()),
+            (0, [(2, 2), (1, 4), (0, 6)].iter().cloned().collect()),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        assert_eq!(m, expected);
+
+        let m = c! {x => y => z => x + y + z for x in 0..2 for y in 0..2 for z in 0..2};
+        assert_eq!(m[&1][&0][&1], 2);
+        assert_eq!(m.len(), 2);
+        assert_eq!(m[&0].len(), 2);
+
+        let m = c! {x: y: move |z: i32| x + y + z for x in 0..2 for y in 0..2};
+        assert_eq!(m[&1][&0](1), 2);
+    }
+
+    #[test]
+    fn nested_map_inner_behavior() {
+        use crate::DuplicateKey;
+        use std::collections::BTreeMap;
+
+        let m = c! {x % 2: x % 3: += x for x in 1..=12};
+        assert_eq!(m[&1][&1], 1 + 7);
+        assert_eq!(m[&0][&0], 6 + 12);
+
+        let m = c! {x % 2: x % 3: group x for x in 1..=12};
+        assert_eq!(m[&1][&1], vec![1, 7]);
+
+        let m = c! {on_duplicate(first); x % 2: x % 3: x for x in 1..=12};
+        assert_eq!(m[&0][&0], 6);
+
+        let m = c! {on_duplicate(error); x % 2: x % 3: x for x in 1..=12};
+        assert_eq!(m, Err(DuplicateKey(1)));
+
+        let m = c! {BTreeMap<_, BTreeMap<_, _>>; x % 2: x: x * x for x in 1..=4};
+        assert_eq!(
+            m.into_iter().collect::<Vec<_>>(),
+            vec![
+                (0, [(2, 4), (4, 16)].iter().cloned().collect()),
+                (1, [(1, 1), (3, 9)].iter().cloned().collect())
+            ]
+        );
+    }
+
+    #[test]
+    fn lazy_iter() {
+        let n: i32 = c![iter; x * x for x in 1..=10 if x % 2 == 0].sum();
+        assert_eq!(n, 4 + 16 + 36 + 64 + 100);
+
+        let mut it = c![iter; (x, y) for x in 0..3 if x > 0 for y in x..3];
+        assert_eq!(it.next(), Some((1, 1)));
+        assert_eq!(it.collect::<Vec<_>>(), vec![(1, 2), (2, 2)]);
+
+        let v = vec![1, 2, 3];
+        let v = &v;
+        assert!(c![iter; x * y == 6 for &x in v for &y in v].any(|b| b));
+
+        let pairs: Vec<_> = c! {iter; x: x * 10 for x in 1..=2}.collect();
+        assert_eq!(pairs, vec![(1, 10), (2, 20)]);
+    }
+
+    #[test]
+    fn lazy_iter_move() {
+        fn multiples(words: Vec<String>, n: usize) -> impl Iterator<Item = String> {
+            c![move iter; w.repeat(i) for w in words for i in 1..=n]
+        }
+        assert_eq!(
+            multiples(vec!["a".into(), "b".into()], 2).collect::<Vec<_>>(),
+            vec!["a", "aa", "b", "bb"]
+        );
+    }
+
+    // Async
+    #[cfg(feature = "futures")]
+    #[test]
+    fn for_await() {
+        use futures::executor::block_on;
+        use futures::stream;
+
+        block_on(async {
+            let v = c![x * y for await x in stream::iter(1..=3) if x != 2 for y in 0..2];
+            assert_eq!(v, vec![0, 1, 0, 3]);
+
+            let m = c! {x: y for x in 0..2 for await y in stream::iter(x..3)};
+            assert_eq!(m, [(0, 2), (1, 2)].iter().cloned().collect());
+
+            let mut n = 0;
+            c![n += x; for await x in stream::iter(vec![1, 2, 3])];
+            assert_eq!(n, 6);
+
+            let v = c![x for await Some(x) in stream::iter(vec![Some(1), None, Some(3)])];
+            assert_eq!(v, vec![1, 3]);
+        });
+    }
+
+    #[cfg(feature = "futures")]
+    struct YieldNow(bool);
+
+    #[cfg(feature = "futures")]
+    impl std::future::Future for YieldNow {
+        type Output = ();
+
+        fn poll(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context,
+        ) -> std::task::Poll<()> {
+            if self.0 {
+                return std::task::Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        }
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn buffered() {
+        use futures::executor::block_on;
+        use std::cell::Cell;
+
+        let active = Cell::new(0);
+        let max_active = Cell::new(0);
+        let task = |x: i32, delay: usize| {
+            let (active, max_active) = (&active, &max_active);
+            async move {
+                active.set(active.get() + 1);
+                max_active.set(max_active.get().max(active.get()));
+                for _ in 0..delay {
+                    YieldNow(false).await;
+                }
+                active.set(active.get() - 1);
+                x * 10
+            }
+        };
+
+        block_on(async {
+            let v = c![buffered(2); task(x, 3 - x as usize) for x in 0..4 if x != 1];
+            assert_eq!(v, vec![0, 20, 30]);
+            assert_eq!(max_active.get(), 2);
+
+            max_active.set(0);
+            let v = c![buffer_unordered(3); task(x, 3 - x as usize) for x in 0..3];
+            assert_eq!(v, vec![20, 10, 0]);
+            assert_eq!(max_active.get(), 3);
+
+            let m = c! {buffer_unordered(2); x: task(x, 1) for x in 0..3};
+            assert_eq!(m, [(0, 0), (1, 10), (2, 20)].iter().cloned().collect());
+
+            max_active.set(0);
+            let s = c! {set; buffered(1); task(x % 2, 0) for x in 0..5};
+            assert_eq!(s, [0, 10].iter().cloned().collect());
+            assert_eq!(max_active.get(), 1);
+        });
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn stream() {
+        use futures::executor::block_on;
+        use futures::stream::{self, StreamExt};
+
+        async fn is_odd(x: i32) -> bool {
+            YieldNow(false).await;
+            x % 2 == 1
+        }
+
+        fn squares(n: i32) -> impl futures::Stream<Item = i32> {
+            c![stream; x * x for x in 0..n if is_odd(x).await]
+        }
+
+        block_on(async {
+            assert_eq!(squares(6).collect::<Vec<_>>().await, vec![1, 9, 25]);
+
+            let s = c![stream; (x, y) for await x in stream::iter(0..2) for y in x..2];
+            assert_eq!(s.collect::<Vec<_>>().await, vec![(0, 0), (0, 1), (1, 1)]);
+
+            let mut s = c! {stream; x: async { x * 10 }.await for x in 1..=2};
+            assert_eq!(s.next().await, Some((1, 10)));
+            assert_eq!(s.next().await, Some((2, 20)));
+            assert_eq!(s.next().await, None);
+            assert_eq!(s.next().await, None);
+        });
+    }
+
+    // Parallel
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par() {
+        use std::collections::{BTreeMap, HashMap, HashSet};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let xs: Vec<u64> = (0..1000).collect();
+
+        assert_eq!(
+            c![par; x * y for &x in &xs if x % 3 == 0 for y in 0..x % 5],
+            c![x * y for &x in &xs if x % 3 == 0 for y in 0..x % 5]
+        );
+        let opts: Vec<_> = c![if x % 4 == 0 { None } else { Some(x) } for &x in &xs];
+        assert_eq!(c![par; x for &Some(x) in &opts], c![x for &Some(x) in &opts]);
+        let m: HashMap<_, _> = c! {par; x % 7: x for &x in &xs};
+        assert_eq!(m, c! {x % 7: x for &x in &xs});
+        let s: HashSet<_> = c! {par; set; x % 11 for &x in &xs};
+        assert_eq!(s, c! {set; x % 11 for &x in &xs});
+        assert_eq!(
+            c! {par; BTreeMap<_, _>; x: x * x for &x in &xs},
+            c! {BTreeMap<_, _>; x: x * x for &x in &xs}
+        );
+
+        let total = AtomicUsize::new(0);
+        c![par; total.fetch_add(x as usize, Ordering::Relaxed); for &x in &xs];
+        assert_eq!(total.into_inner(), c![x as usize for &x in &xs].iter().sum());
+    }
+
+    // Other collections
+    #[test]
+    fn btree_map() {
+        use std::collections::BTreeMap;
+
+        let m = c! {BTreeMap<_, _>; x: x * x for x in (1..=5).rev() if x != 3};
+        assert_eq!(
+            m.into_iter().collect::<Vec<_>>(),
+            vec![(1, 1), (2, 4), (4, 16), (5, 25)]
+        );
+    }
+
+    #[test]
+    fn btree_set() {
+        use std::collections::BTreeSet;
+
+        let s = c![BTreeSet<_>; (x * y) % 5 for x in 1..=3 for y in 1..=3];
+        assert_eq!(s.into_iter().collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn vec_deque() {
+        use std::collections::VecDeque;
+
+        let d = c![VecDeque<_>; x for x in 1..=3];
+        assert_eq!(d, VecDeque::from(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn binary_heap() {
+        use std::collections::BinaryHeap;
+
+        let h = c![BinaryHeap<_>; x % 7 for x in 1..=10];
+        assert_eq!(h.into_sorted_vec(), vec![0, 1, 1, 2, 2, 3, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn boxed_slice() {
+        let b = c![Box<[_]>; x * x for x in 1..=4];
+        assert_eq!(b, vec![1, 4, 9, 16].into_boxed_slice());
+
+        let r = c![std::rc::Rc<[_]>; (x, y) for x in 1..=2 for y in 'a'..='b'];
+        assert_eq!(*r, [(1, 'a'), (1, 'b'), (2, 'a'), (2, 'b')]);
+    }
+
+    #[test]
+    fn string() {
+        let s = c![String; ch.to_ascii_uppercase() for ch in "comprende".chars() if ch != 'e'];
+        assert_eq!(s, "COMPRND");
+    }
+
+    #[test]
+    fn inferred_collection() {
+        use std::collections::{BTreeMap, BTreeSet};
+
+        let s: BTreeSet<_> = c![_; x % 3 for x in 1..=10];
+        assert_eq!(s.into_iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        let m: BTreeMap<_, _> = c! {_; x % 3: x for x in 1..=10};
+        assert_eq!(
+            m.into_iter().collect::<Vec<_>>(),
+            vec![(0, 9), (1, 10), (2, 8)]
+        );
+    }
+
+    // Array
+    #[test]
+    fn array() {
+        let a = c![[u8; 8]; (x * 3) as u8 for x in 0..8];
+        assert_eq!(a, [0, 3, 6, 9, 12, 15, 18, 21]);
+
+        let a = c![[_; 4]; (x, y) for x in 1..=2 for y in 'a'..='b'];
+        assert_eq!(a, [(1, 'a'), (1, 'b'), (2, 'a'), (2, 'b')]);
+    }
+
+    #[test]
+    #[should_panic(expected = "array comprehension produced 5 elements, expected 6")]
+    fn array_too_short() {
+        c![[i32; 6]; x for x in 1..=10 if x % 2 == 0];
+    }
+
+    #[test]
+    #[should_panic(expected = "array comprehension produced more than 3 elements")]
+    fn array_too_long() {
+        c![[i32; 3]; x for x in 1..];
+    }
+
+    // String
+    #[test]
+    fn join() {
+        let s = c![join; x for x in 1..=5];
+        assert_eq!(s, "12345");
+
+        let s = c![join(", "); x * x for x in 1..=5 if x != 3];
+        assert_eq!(s, "1, 4, 16, 25");
+
+        let s = c![join(' ', "<", ">"); format!("{}{}", x, y) for x in 1..=2 for y in 'a'..='b'];
+        assert_eq!(s, "<1a 1b 2a 2b>");
+
+        let s = c![join(", ", "[", "]"); x for x in 1..=5 if x > 5];
+        assert_eq!(s, "[]");
+    }
+
+    #[test]
+    fn display() {
+        use std::cell::Cell;
+
+        let runs = Cell::new(0);
+        let v = vec![1.0, 2.5, 4.25];
+        let d = c![display(", ", "(", ")"); {runs.set(runs.get() + 1); x / 2.0} for x in &v];
+        assert_eq!(runs.get(), 0);
+
+        assert_eq!(format!("{}", d), "(0.5, 1.25, 2.125)");
+        assert_eq!(format!("{:.1}", d), "(0.5, 1.2, 2.1)");
+        assert_eq!(format!("{:>5}", d), "(  0.5,  1.25, 2.125)");
+        assert_eq!(runs.get(), 9);
+
+        let d = c![display; x for x in 1..=3 for _ in 0..x];
+        assert_eq!(d.to_string(), "122333");
+    }
+
     // Statement
     #[test]
     fn simple_stmt() {